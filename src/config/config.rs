@@ -25,3 +25,23 @@ pub struct BVideoInfo {
     pub current_page: usize,
     pub plist: Vec<String>,
 }
+
+/// The `-progress` report of the running ffmpeg.
+#[derive(Clone, Default)]
+pub struct FfProgress {
+    pub fps: f64,
+    pub bitrate: f64, // in kbit/s
+    pub total_size: u64,
+    pub out_time: f64, // in seconds
+    pub speed: f64,
+}
+
+/// What ffprobe found in the source.
+#[derive(Clone, Default)]
+pub struct ProbeInfo {
+    pub video_codec: String,
+    pub audio_codec: String,
+    pub width: u64,
+    pub height: u64,
+    pub fps: f64,
+}
@@ -2,20 +2,37 @@ pub mod config;
 
 use crate::utils::is_android;
 use clap::Parser;
-use config::{BVideoInfo, BVideoType, Config};
+use log::warn;
+use config::{BVideoInfo, BVideoType, Config, FfProgress, ProbeInfo};
 use reqwest::Url;
 use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::IsTerminal;
+use std::ops::Not;
 use std::path::Path;
+use std::time::Instant;
 use tokio::{fs::OpenOptions, io::AsyncWriteExt};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
+#[clap(group(clap::ArgGroup::new("burn_target").args(["serve_hls", "restream"]).multiple(true)))]
 pub struct Args {
     /// Set the http url
     #[clap(short = 'u', long, value_parser, value_name = "URL")]
     url: String,
 
+    /// Play this stream url or local file instead of the stream of the room, the danmaku still come from the room
+    #[clap(long, value_parser, value_name = "URL|FILE")]
+    video: Option<String>,
+
+    /// Danmaku of a local video: Bilibili XML, JSON lines or ASS; by default a file next to it with the same name
+    #[clap(long = "danmaku-file", value_parser, value_name = "FILE")]
+    danmaku_file: Option<String>,
+
+    /// Delay the danmaku by this many seconds, to line the chat up with the --video
+    #[clap(long = "chat-offset", value_parser, value_name = "SECONDS")]
+    chat_offset: Option<f64>,
+
     #[clap(short = 'r', long, action)]
     record: bool,
 
@@ -32,6 +49,58 @@ pub struct Args {
     #[clap(long = "http-address", value_parser)]
     http_address: Option<String>,
 
+    /// Publish the live danmaku as server-sent events on this address, with an OBS overlay page at /overlay
+    #[clap(long = "danmaku-feed", value_parser, value_name = "ADDRESS")]
+    danmaku_feed: Option<String>,
+
+    /// Also serve the stream as fMP4 HLS under /hls/ of the http address, for browsers and phones
+    #[clap(long = "serve-hls", action, requires = "http_address")]
+    serve_hls: bool,
+
+    /// Burn the danmaku into the video of the HLS output and the restream targets (re-encodes with x264)
+    #[clap(long = "burn-danmaku", action, requires = "burn_target")]
+    burn_danmaku: bool,
+
+    /// Relay the stream to an rtmp://, srt:// or udp:// url, or into a file (strftime patterns allowed); repeatable
+    #[clap(long, value_parser, value_name = "TARGET")]
+    restream: Vec<String>,
+
+    /// Also show the chat of another live room, e.g. the same stream on another site; repeatable
+    #[clap(long = "extra-chat", value_parser, value_name = "URL")]
+    extra_chat: Vec<String>,
+
+    /// Color of the danmaku from one site, e.g. "twitch=9146ff"; the tags are bili, douyu, huya, twitch and yt
+    #[clap(long = "chat-color", value_parser, value_name = "TAG=RRGGBB")]
+    chat_color: Vec<String>,
+
+    /// Burn the danmaku into finished recordings with x264; a local recording given as the url is rendered directly
+    #[clap(long, action)]
+    render: bool,
+
+    /// Output height of the rendered video, the source height if not set
+    #[clap(long = "render-height", value_parser, value_name = "HEIGHT")]
+    render_height: Option<u64>,
+
+    /// x264 crf of the rendered video
+    #[clap(long = "render-crf", default_value_t = 23, value_parser)]
+    render_crf: u8,
+
+    /// x264 preset of the rendered video
+    #[clap(long = "render-preset", default_value = "veryfast", value_parser)]
+    render_preset: String,
+
+    /// Play or record the audio only, --tui shows the danmaku alongside
+    #[clap(long = "audio-only", action)]
+    audio_only: bool,
+
+    /// Accept JSON commands on this unix socket, e.g. {"cmd": "status"}
+    #[clap(long, value_parser, value_name = "PATH")]
+    control: Option<String>,
+
+    /// Show the danmaku and the stream status in a full-screen terminal view
+    #[clap(long, action)]
+    tui: bool,
+
     /// Do not print danmaku
     #[clap(short = 'q', long, action)]
     quiet: bool,
@@ -41,11 +110,44 @@ pub struct Args {
 
     #[clap(long, action)]
     plive: bool,
+
+    /// Prefer HLS over DASH when a site offers both (lower CPU use)
+    #[clap(long = "prefer-hls", action)]
+    prefer_hls: bool,
+
+    /// Use low-latency HLS (partial segments, blocking playlist reloads) when the stream offers it
+    #[clap(long = "low-latency", action)]
+    low_latency: bool,
+
+    /// HLS variant selector, e.g. "720p", "720p60", "audio_only", "codec=avc1", "fps=30", "lang=en"; comma separated
+    #[clap(long = "hls-variant", value_parser)]
+    hls_variant: Option<String>,
+
+    /// Number of HLS segments to download in parallel
+    #[clap(long = "hls-prefetch", value_parser)]
+    hls_prefetch: Option<usize>,
+
+    /// Switch to an alternative playlist during Twitch ad breaks instead of holding the last frame
+    #[clap(long = "ad-fallback", action)]
+    ad_fallback: bool,
+
+    /// Keep the last N minutes of a live stream on disk so that it can be paused and rewound in mpv
+    #[clap(long, value_parser, value_name = "MINUTES")]
+    timeshift: Option<u64>,
     // /// Use the Cookies that extracted from browser, could be "chrome" "chromium" or "firefox"
     // #[clap(long = "cookies-from-browser", value_parser)]
     // cookies_from_browser: Option<String>,
 }
 
+/// Looks for "video.xml", "video.jsonl", "video.json" or "video.ass" next to "video.mp4".
+fn find_danmaku_file(video: &str) -> Option<String> {
+    ["xml", "jsonl", "json", "ass"]
+        .iter()
+        .map(|ext| Path::new(video).with_extension(ext))
+        .find(|it| it.is_file())
+        .map(|it| it.to_string_lossy().to_string())
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Platform {
     Linux,
@@ -67,9 +169,10 @@ pub enum StreamType {
     FLV,
     HLS(usize),
     DASH,
+    MPD, // for the generic dash streamer
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Site {
     BiliLive,
     BiliVideo,
@@ -77,7 +180,41 @@ pub enum Site {
     DouyuLive,
     HuyaLive,
     TwitchLive,
+    TwitchVideo,
     YoutubeLive,
+    Local,
+}
+
+impl Site {
+    /// The live site of a room url, for the extra chat rooms.
+    pub fn from_live_url(url: &str) -> Option<Self> {
+        if url.contains("live.bilibili.com/") {
+            Some(Site::BiliLive)
+        } else if url.contains("douyu.com/") {
+            Some(Site::DouyuLive)
+        } else if url.contains("huya.com/") {
+            Some(Site::HuyaLive)
+        } else if url.contains("twitch.tv/") && url.contains("twitch.tv/videos/").not() {
+            Some(Site::TwitchLive)
+        } else if url.contains("youtube.com/") {
+            Some(Site::YoutubeLive)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the messages of each room when the chats of several rooms are merged.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Site::BiliLive | Site::BiliVideo => "bili",
+            Site::BahaVideo => "baha",
+            Site::DouyuLive => "douyu",
+            Site::HuyaLive => "huya",
+            Site::TwitchLive | Site::TwitchVideo => "twitch",
+            Site::YoutubeLive => "yt",
+            Site::Local => "local",
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -91,6 +228,22 @@ pub struct ConfigManager {
     pub bcookie: String,
     pub cookies_from_browser: String,
     pub plive: bool,
+    pub prefer_hls: bool,
+    pub low_latency: bool,
+    pub hls_variant: String,
+    pub hls_prefetch: usize,
+    pub throughput: Cell<u64>, // of the video stream, in bit/s
+    pub downshifted: Cell<bool>,
+    pub ad_fallback: bool,
+    pub timeshift: u64, // in minutes, 0 to disable
+    pub paused: Cell<bool>, // the player is paused on a timeshifted stream, the watchdogs wait for it
+    pub audio_only: bool,
+    pub chat_ui: bool,
+    pub stream_idle: Cell<u64>, // seconds the streamer watchdog has waited for data
+    pub restart_pre_ff: Cell<bool>, // the next end of the pre ffmpeg input is a discontinuity, not the end
+    pub control: Option<String>,
+    pub started: Instant,
+    danmaku_stamps: RefCell<VecDeque<Instant>>, // of the messages of the last minute
     pub quiet: bool,
     pub wait_interval: u64,
     pub font_scale: Cell<f64>,
@@ -99,6 +252,21 @@ pub struct ConfigManager {
     pub display_fps: Cell<(u64, u64)>,
     pub room_url: String,
     pub http_address: Option<String>,
+    pub danmaku_feed: Option<String>,
+    pub serve_hls: bool,
+    pub burn_danmaku: bool,
+    pub restream: Vec<String>,
+    pub extra_chat: Vec<(Site, String)>,
+    pub external_video: Option<String>,
+    pub danmaku_file: Option<String>,
+    pub chat_offset: i64, // in ms
+    pub direct_input: Cell<bool>, // ffmpeg opens the external video itself
+    pub chat_colors: HashMap<String, String>,
+    pub render: bool,
+    pub record: bool, // --record itself, not implied by --restream or --http-address
+    pub render_height: u64, // 0 to keep the source height
+    pub render_crf: u8,
+    pub render_preset: String,
     pub run_mode: RunMode,
     pub record_mode: RecordMode,
     pub site: Site,
@@ -106,6 +274,8 @@ pub struct ConfigManager {
     pub stream_type: Cell<StreamType>,
     pub bvideo_info: RefCell<BVideoInfo>,
     pub title: RefCell<String>,
+    pub ff_progress: RefCell<FfProgress>,
+    pub probe_info: RefCell<Option<ProbeInfo>>,
     on_writing: Cell<bool>,
 }
 
@@ -126,7 +296,10 @@ impl ConfigManager {
         let c = config::load_config(&c).unwrap();
         let room_url = args.url.clone();
         let mut site_type = SiteType::Live;
-        let site = if room_url.contains("live.bilibili.com/") {
+        let site = if Path::new(&room_url).is_file() {
+            site_type = SiteType::Video;
+            Site::Local
+        } else if room_url.contains("live.bilibili.com/") {
             Site::BiliLive
         } else if room_url.contains("bilibili.com/") {
             let u = Url::parse(&room_url).unwrap();
@@ -158,6 +331,9 @@ impl ConfigManager {
             Site::DouyuLive
         } else if room_url.contains("huya.com/") {
             Site::HuyaLive
+        } else if room_url.contains("twitch.tv/videos/") {
+            site_type = SiteType::Video;
+            Site::TwitchVideo
         } else if room_url.contains("twitch.tv/") {
             Site::TwitchLive
         } else if room_url.contains("youtube.com/") {
@@ -165,7 +341,12 @@ impl ConfigManager {
         } else {
             panic!("unknown url")
         };
-        let run_mode = if args.record || args.http_address.is_some() || args.download_dm {
+        let run_mode = if args.record
+            || args.render
+            || args.http_address.is_some()
+            || args.download_dm
+            || args.restream.is_empty().not()
+        {
             RunMode::Record
         } else {
             RunMode::Play
@@ -188,7 +369,62 @@ impl ConfigManager {
             bvideo_info: RefCell::new(bvinfo),
             bcookie: c.bcookie.unwrap_or_else(|| "".into()),
             http_address: args.http_address.as_ref().map(|it| it.into()),
+            danmaku_feed: args.danmaku_feed.clone(),
+            serve_hls: args.serve_hls,
+            burn_danmaku: args.burn_danmaku,
+            restream: args.restream.clone(),
+            extra_chat: args
+                .extra_chat
+                .iter()
+                .filter_map(|it| match Site::from_live_url(it) {
+                    Some(site) => Some((site, it.clone())),
+                    None => {
+                        warn!("unsupported chat room: {}", it);
+                        None
+                    }
+                })
+                .collect(),
+            external_video: args.video.clone(),
+            danmaku_file: match site {
+                Site::Local => args.danmaku_file.clone().or_else(|| find_danmaku_file(&room_url)),
+                _ => None,
+            },
+            chat_offset: (args.chat_offset.unwrap_or(0.0) * 1000.0) as i64,
+            direct_input: Cell::new(false),
+            chat_colors: args
+                .chat_color
+                .iter()
+                .filter_map(|it| it.split_once('='))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().trim_start_matches('#').to_lowercase()))
+                .filter(|(_, v)| v.len() == 6 && v.chars().all(|c| c.is_ascii_hexdigit()))
+                .collect(),
+            render: args.render,
+            record: args.record,
+            render_height: args.render_height.unwrap_or(0),
+            render_crf: args.render_crf,
+            render_preset: args.render_preset.clone(),
             plive: args.plive,
+            prefer_hls: args.prefer_hls,
+            low_latency: args.low_latency,
+            hls_variant: match args.hls_variant.as_ref() {
+                Some(it) => it.clone(),
+                None if args.audio_only => "audio_only".into(),
+                None => "".into(),
+            },
+            hls_prefetch: args.hls_prefetch.unwrap_or(1).clamp(1, 8),
+            throughput: Cell::new(0),
+            downshifted: Cell::new(false),
+            ad_fallback: args.ad_fallback,
+            timeshift: args.timeshift.unwrap_or(0),
+            paused: Cell::new(false),
+            audio_only: args.audio_only,
+            // without a terminal the danmaku stay plain lines
+            chat_ui: args.tui && args.quiet.not() && std::io::stdout().is_terminal(),
+            stream_idle: Cell::new(0),
+            restart_pre_ff: Cell::new(false),
+            control: args.control.clone(),
+            started: Instant::now(),
+            danmaku_stamps: RefCell::new(VecDeque::new()),
             quiet: args.quiet,
             wait_interval: args.wait_interval.unwrap_or(0),
             on_writing: Cell::new(false),
@@ -196,6 +432,8 @@ impl ConfigManager {
             cookies_from_browser: c.cookies_from_browser.unwrap_or_else(|| "".into()),
             display_fps: Cell::new((60, 0)),
             title: RefCell::new("".to_string()),
+            ff_progress: RefCell::new(FfProgress::default()),
+            probe_info: RefCell::new(None),
         }
     }
 
@@ -207,7 +445,13 @@ impl ConfigManager {
     }
 
     pub fn set_stream_type(&self, stream_info: &HashMap<&str, String>) {
-        if stream_info["url"].contains(".m3u8") {
+        // youtube has a streamer of its own
+        if self.site != Site::YoutubeLive
+            && (stream_info["url"].contains(".mpd")
+                || stream_info.get("content_type").is_some_and(|it| it.starts_with("application/dash+xml")))
+        {
+            self.stream_type.set(StreamType::MPD);
+        } else if stream_info["url"].contains(".m3u8") {
             if self.site == Site::BiliLive {
                 self.stream_type.set(StreamType::HLS(1)); // for m4s inside
             } else {
@@ -218,9 +462,51 @@ impl ConfigManager {
         } else {
             self.stream_type.set(StreamType::DASH);
         }
-        if matches!(self.site, Site::BiliVideo) {
+        if matches!(self.site, Site::BiliVideo | Site::TwitchVideo) {
             self.stream_type.set(StreamType::DASH);
         }
+        if let Some(v) = self.external_video.as_ref() {
+            if self.stream_type.get() == StreamType::HLS(1) {
+                self.stream_type.set(StreamType::HLS(0));
+            }
+            // files and anything but hls and flv are not for the streamers
+            self.direct_input.set(Path::new(v).is_file() || self.stream_type.get() == StreamType::DASH);
+        }
+    }
+
+    /// Drops the stamps older than a minute, so that they do not pile up when nothing asks for the rate.
+    fn prune_danmaku_stamps(stamps: &mut VecDeque<Instant>) {
+        while stamps.front().is_some_and(|it| it.elapsed().as_secs() >= 60) {
+            stamps.pop_front();
+        }
+    }
+
+    pub fn count_danmaku(&self) {
+        let mut stamps = self.danmaku_stamps.borrow_mut();
+        Self::prune_danmaku_stamps(&mut stamps);
+        stamps.push_back(Instant::now());
+    }
+
+    /// Live danmaku received in the last minute.
+    pub fn danmaku_rate(&self) -> usize {
+        let mut stamps = self.danmaku_stamps.borrow_mut();
+        Self::prune_danmaku_stamps(&mut stamps);
+        stamps.len()
+    }
+
+    /// Names an output file after the title and the current time, e.g. "title - 2024-01-01 12:00:00.mkv".
+    pub fn output_file_name(&self, ext: &str) -> String {
+        let title = self.title.borrow();
+        let max_len = match title.char_indices().nth(70) {
+            Some(it) => it.0,
+            None => title.len(),
+        };
+        format!(
+            "{} - {}.{}",
+            title[..max_len].replace('/', "-"),
+            chrono::Local::now().format("%F %T"),
+            ext
+        )
     }
 
     pub async fn write_config(&self) -> anyhow::Result<()> {
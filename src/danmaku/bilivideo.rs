@@ -4,6 +4,41 @@ use tokio::io::AsyncWriteExt;
 
 use super::DMLDanmaku;
 
+/// The `<d p="time,mode,size,color,...">text</d>` elements of a Bilibili danmaku XML.
+pub fn parse_xml(buf: &str) -> anyhow::Result<Vec<DMLDanmaku>> {
+    let mut ret = Vec::new();
+    let doc = roxmltree::Document::parse(buf)?;
+    let elem_dm: Vec<roxmltree::Node> = doc.descendants().filter(|n| n.tag_name().name() == "d").collect();
+    for e in elem_dm {
+        if e.has_attribute("p") {
+            let tmps: Vec<&str> = e.attribute("p").unwrap().split(',').collect();
+            // downloaded files are not always well-formed
+            if tmps.len() < 4 {
+                continue;
+            }
+            let text = e.text().unwrap_or("");
+            let time = (tmps[0].parse::<f64>().unwrap_or(0.0) * 1000.0) as i64;
+            let position = if tmps[1].eq("4") {
+                2
+            } else if tmps[1].eq("5") {
+                8
+            } else {
+                0
+            };
+            let color = format!("{:06x}", tmps[3].parse::<u64>().unwrap_or(16777215));
+            let dml_dm = DMLDanmaku {
+                time,
+                text: text.trim().to_string(),
+                nick: "".to_string(),
+                color: color.to_string(),
+                position,
+            };
+            ret.push(dml_dm);
+        }
+    }
+    Ok(ret)
+}
+
 pub struct Bilibili {}
 
 impl Bilibili {
@@ -28,36 +63,8 @@ impl Bilibili {
         dp.shutdown().await?;
         let dp = dp.into_inner();
         let buf = String::from_utf8_lossy(&dp);
-        let doc = roxmltree::Document::parse(&buf)?;
-        let elem_dm: Vec<roxmltree::Node> = doc.descendants().filter(|n| n.tag_name().name() == "d").collect();
-        for e in elem_dm {
-            if e.has_attribute("p") {
-                let tmps: Vec<&str> = e.attribute("p").unwrap().split(',').collect();
-                let text = e.text().unwrap_or("");
-                let time = (tmps[0].parse::<f64>().unwrap() * 1000.0) as i64;
-                let position = if tmps[1].eq("4") {
-                    2
-                } else if tmps[1].eq("5") {
-                    8
-                } else {
-                    0
-                };
-                let color = format!("{:06x}", tmps[3].parse::<u64>().unwrap_or(16777215));
-                let dml_dm = DMLDanmaku {
-                    time,
-                    text: text.trim().to_string(),
-                    nick: "".to_string(),
-                    color: color.to_string(),
-                    position,
-                };
-                dtx.send(dml_dm).await?;
-                // dtx.send((
-                //     format!("{:06x}", tmps[3].parse::<u64>().unwrap_or(16777215)),
-                //     format!("{},{}", tmps[0], tmps[1]),
-                //     e.text().unwrap_or("").into(),
-                // ))
-                // .await?;
-            }
+        for dml_dm in parse_xml(&buf)? {
+            dtx.send(dml_dm).await?;
         }
         dtx.close();
         Ok(())
@@ -0,0 +1,267 @@
+use super::DMLDanmaku;
+use crate::config::RunMode;
+use crate::dmlive::{DMLContext, DMLMessage};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{IsTerminal, Write},
+    ops::Not,
+    rc::Rc,
+};
+use tokio::io::AsyncReadExt;
+
+const SCROLLBACK: usize = 2000;
+
+struct ChatLine {
+    time: String,
+    nick: String,
+    text: String,
+    color: (u8, u8, u8),
+    superchat: bool,
+}
+
+/// Rough terminal width of a string, the same guess the danmaku layout makes: wide for anything but ascii.
+fn display_width(s: &str) -> usize {
+    s.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum()
+}
+
+/// Cuts a string into pieces that fit in `width` columns.
+fn wrap(s: &str, width: usize) -> Vec<String> {
+    let mut ret = vec![String::new()];
+    let mut w = 0;
+    for c in s.chars() {
+        let cw = if c.is_ascii() { 1 } else { 2 };
+        if w + cw > width {
+            ret.push(String::new());
+            w = 0;
+        }
+        ret.last_mut().unwrap().push(c);
+        w += cw;
+    }
+    ret
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    wrap(s, width).swap_remove(0)
+}
+
+fn term_size() -> (usize, usize) {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) } == 0 && ws.ws_row > 2 && ws.ws_col > 10 {
+        (ws.ws_row as usize, ws.ws_col as usize)
+    } else {
+        (24, 80)
+    }
+}
+
+/// Shows the chat with a status line in the terminal, or plain lines when stdout is not a terminal.
+pub struct ChatUI {
+    lines: RefCell<VecDeque<ChatLine>>,
+    scroll: Cell<usize>, // number of newer messages hidden below the view, 0 follows the chat
+    dirty: Cell<bool>,
+    active: Cell<bool>,
+    termios: RefCell<Option<libc::termios>>,
+    ctx: Rc<DMLContext>,
+}
+
+impl ChatUI {
+    pub fn new(ctx: Rc<DMLContext>) -> Self {
+        Self {
+            lines: RefCell::new(VecDeque::new()),
+            scroll: Cell::new(0),
+            dirty: Cell::new(false),
+            active: Cell::new(false),
+            termios: RefCell::new(None),
+            ctx,
+        }
+    }
+
+    pub fn push(&self, dm: &DMLDanmaku) {
+        if self.ctx.cm.chat_ui.not() {
+            println!("[{}] {}", &dm.nick, &dm.text);
+            return;
+        }
+        let c = u32::from_str_radix(&dm.color, 16).unwrap_or(0xffffff);
+        let mut lines = self.lines.borrow_mut();
+        if lines.len() >= SCROLLBACK {
+            lines.pop_front();
+        }
+        lines.push_back(ChatLine {
+            time: chrono::Local::now().format("%T").to_string(),
+            nick: dm.nick.clone(),
+            text: dm.text.clone(),
+            color: ((c >> 16) as u8, (c >> 8) as u8, c as u8),
+            superchat: dm.text.starts_with("[SC]"),
+        });
+        // keep the view where it is while reading back
+        if self.scroll.get() > 0 {
+            self.scroll.update(|it| (it + 1).min(lines.len() - 1));
+        }
+        self.dirty.set(true);
+    }
+
+    fn status_line(&self) -> String {
+        let mut ret = format!(" {}", self.ctx.cm.title.borrow());
+        {
+            let p = self.ctx.cm.ff_progress.borrow();
+            if p.bitrate > 0.0 {
+                let _ = write!(ret, " | {:.0} kbit/s {:.1}x", p.bitrate, p.speed);
+            }
+        }
+        let _ = write!(ret, " | {}/min", self.ctx.cm.danmaku_rate());
+        match self.ctx.cm.stream_idle.get() {
+            0 | 1 => ret.push_str(" | stream ok"),
+            it => {
+                let _ = write!(ret, " | no data for {}s", it);
+            }
+        }
+        ret
+    }
+
+    fn render_line(line: &ChatLine, width: usize, out: &mut Vec<String>) {
+        if line.superchat {
+            let (r, g, b) = line.color;
+            for it in wrap(
+                &format!("{} {}: {}", &line.time, &line.nick, &line.text),
+                width,
+            )
+            .into_iter()
+            .rev()
+            {
+                let pad = width.saturating_sub(display_width(&it));
+                out.push(format!(
+                    "\x1b[1;30;48;2;{r};{g};{b}m{}{}\x1b[0m",
+                    it,
+                    " ".repeat(pad)
+                ));
+            }
+            return;
+        }
+        let (r, g, b) = line.color;
+        let nick = truncate(&line.nick, width.saturating_sub(10).max(1));
+        let rest = width.saturating_sub(display_width(&line.time) + display_width(&nick) + 3);
+        out.push(format!(
+            "\x1b[2m{}\x1b[0m \x1b[1;38;2;{r};{g};{b}m{}\x1b[0m: {}",
+            &line.time,
+            nick,
+            truncate(&line.text, rest.max(1))
+        ));
+    }
+
+    fn redraw(&self) {
+        let (rows, cols) = term_size();
+        let height = rows - 2;
+        let mut chat = Vec::new();
+        {
+            let lines = self.lines.borrow();
+            for line in lines.iter().rev().skip(self.scroll.get()) {
+                Self::render_line(line, cols, &mut chat);
+                if chat.len() >= height {
+                    break;
+                }
+            }
+        }
+        chat.truncate(height);
+        let mut out = String::from("\x1b[H");
+        let _ = write!(
+            out,
+            "\x1b[7m{:<cols$}\x1b[0m\r\n",
+            truncate(&self.status_line(), cols)
+        );
+        for _ in chat.len()..height {
+            out.push_str("\x1b[K\r\n");
+        }
+        for it in chat.iter().rev() {
+            let _ = write!(out, "{}\x1b[K\r\n", it);
+        }
+        let hint = match self.scroll.get() {
+            0 if matches!(self.ctx.cm.run_mode, RunMode::Record) => "j/k, PgUp/PgDn: scroll  G: follow  m: mark".to_string(),
+            0 => "j/k, PgUp/PgDn: scroll  G: follow".to_string(),
+            it => format!("-- {} newer messages below, G to follow --", it),
+        };
+        let _ = write!(out, "\x1b[2m{}\x1b[0m\x1b[K", truncate(&hint, cols));
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(out.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn on_key(&self, keys: &[u8]) {
+        let page = term_size().0.saturating_sub(3).max(1);
+        let max = self.lines.borrow().len().saturating_sub(1);
+        let scroll = self.scroll.get();
+        let scroll = match keys {
+            b"k" | b"\x1b[A" => scroll + 1,
+            b"j" | b"\x1b[B" => scroll.saturating_sub(1),
+            b"\x1b[5~" | b"b" => scroll + page,
+            b"\x1b[6~" | b" " => scroll.saturating_sub(page),
+            b"g" | b"\x1b[H" => max,
+            b"G" | b"\x1b[F" => 0,
+            b"m" if matches!(self.ctx.cm.run_mode, RunMode::Record) => {
+                let _ = self.ctx.mtx.try_send(DMLMessage::AddMark(String::new()));
+                return;
+            }
+            _ => return,
+        };
+        self.scroll.set(scroll.min(max));
+        self.dirty.set(true);
+    }
+
+    /// Stops echoing and line buffering on the terminal, Ctrl+C keeps working.
+    fn enter(&self) {
+        self.active.set(true);
+        let _ = std::io::stdout().write_all(b"\x1b[?1049h\x1b[?25l");
+        let mut t: libc::termios = unsafe { std::mem::zeroed() };
+        if std::io::stdin().is_terminal() && unsafe { libc::tcgetattr(0, &mut t) } == 0 {
+            *self.termios.borrow_mut() = Some(t);
+            t.c_lflag &= !(libc::ICANON | libc::ECHO);
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, &t) };
+        }
+    }
+
+    pub fn restore(&self) {
+        if self.active.replace(false).not() {
+            return;
+        }
+        if let Some(t) = self.termios.borrow_mut().take() {
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, &t) };
+        }
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+    }
+
+    pub async fn run(&self) {
+        if self.ctx.cm.chat_ui.not() {
+            futures::future::pending::<()>().await;
+        }
+        self.enter();
+        let key_task = async {
+            let mut stdin = tokio::io::stdin();
+            let mut buf = [0u8; 16];
+            while let Ok(n @ 1..) = stdin.read(&mut buf).await {
+                self.on_key(&buf[..n]);
+            }
+            futures::future::pending::<()>().await;
+        };
+        let draw_task = async {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(250));
+            let mut ticks = 0u64;
+            loop {
+                interval.tick().await;
+                ticks += 1;
+                // the status changes without new messages, and log lines may have been drawn over the view
+                if self.dirty.replace(false) || ticks.is_multiple_of(4) {
+                    self.redraw();
+                }
+            }
+        };
+        tokio::join!(key_task, draw_task);
+    }
+}
+
+impl Drop for ChatUI {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
@@ -0,0 +1,206 @@
+use super::{DMLDanmaku, bilivideo};
+use log::info;
+use std::ops::Not;
+
+/// "0:01:02.34" of an ASS event to ms.
+fn parse_ass_time(s: &str) -> Option<i64> {
+    let mut it = s.trim().split(':');
+    let h: f64 = it.next()?.parse().ok()?;
+    let m: f64 = it.next()?.parse().ok()?;
+    let sec: f64 = it.next()?.parse().ok()?;
+    Some(((h * 3600.0 + m * 60.0 + sec) * 1000.0) as i64)
+}
+
+/// Reads one Dialogue line as written by Danmaku2ASS or by dmlive itself.
+fn parse_ass_line(line: &str) -> Option<DMLDanmaku> {
+    let fields: Vec<&str> = line.strip_prefix("Dialogue:")?.splitn(10, ',').collect();
+    if fields.len() < 10 {
+        return None;
+    }
+    let time = parse_ass_time(fields[1])?;
+    // override tags and text apart, the text may as well hold something like "c&"
+    let (mut tags, mut text) = (String::new(), String::new());
+    let mut in_tag = false;
+    for c in fields[9].chars() {
+        match c {
+            '{' => in_tag = true,
+            '}' => in_tag = false,
+            _ if in_tag => tags.push(c),
+            _ => text.push(c),
+        }
+    }
+    // \c&HBBGGRR& from Danmaku2ASS, \1c&bbggrr& from dmlive; \3c is the outline
+    let color = [r"\c&", r"\1c&"]
+        .iter()
+        .find_map(|it| tags.find(it).map(|i| &tags[i + it.len()..]))
+        .and_then(|it| it.trim_start_matches(['H', 'h']).get(..6))
+        .filter(|it| it.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|it| format!("{}{}{}", &it[4..6], &it[2..4], &it[0..2]).to_lowercase())
+        .unwrap_or_else(|| "ffffff".to_string());
+    let position = if tags.contains(r"\move") {
+        0
+    } else if tags.contains(r"\an2") {
+        2
+    } else {
+        8
+    };
+    let text = text.replace(r"\N", " ").replace(r"\h", " ").trim().to_string();
+    // dmlive marks its placeholder events in the name field
+    if text.is_empty() || fields[4] == "dmlive-empty" {
+        return None;
+    }
+    Some(DMLDanmaku {
+        time,
+        text,
+        nick: fields[4].to_string(),
+        color,
+        position,
+    })
+}
+
+/// One object per line: time (ms from the start of the video, or a unix timestamp in ms as the danmaku feed
+/// sends it), text, and optionally nick, color and position.
+fn parse_json_lines(data: &str) -> Vec<DMLDanmaku> {
+    let mut ret = Vec::new();
+    let mut base = None;
+    for line in data.lines().filter(|it| it.trim().is_empty().not()) {
+        let j: serde_json::Value = match serde_json::from_str(line) {
+            Ok(it) => it,
+            Err(_) => continue,
+        };
+        let (Some(mut time), Some(text)) = (
+            j.pointer("/time").and_then(|x| x.as_i64()),
+            j.pointer("/text").and_then(|x| x.as_str()),
+        ) else {
+            continue;
+        };
+        if time > 100_000_000_000 {
+            time -= *base.get_or_insert(time);
+        }
+        let color = j
+            .pointer("/color")
+            .and_then(|x| x.as_str())
+            .filter(|it| it.len() == 6 && it.chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap_or("ffffff");
+        ret.push(DMLDanmaku {
+            time,
+            text: text.to_string(),
+            nick: j.pointer("/nick").and_then(|x| x.as_str()).unwrap_or("").to_string(),
+            color: color.to_string(),
+            position: j.pointer("/position").and_then(|x| x.as_u64()).unwrap_or(0) as u8,
+        });
+    }
+    ret
+}
+
+pub struct Local {}
+
+impl Local {
+    pub fn new() -> Self {
+        Local {}
+    }
+
+    /// Sends every danmaku of a Bilibili XML, JSON lines or ASS file, then closes the channel.
+    pub async fn run(&self, path: &str, dtx: async_channel::Sender<DMLDanmaku>) -> anyhow::Result<()> {
+        let data = tokio::fs::read(path).await?;
+        let data = String::from_utf8_lossy(&data);
+        let head = data.trim_start_matches('\u{feff}').trim_start();
+        let dms = if head.starts_with('<') {
+            bilivideo::parse_xml(head)?
+        } else if head.starts_with('{') {
+            parse_json_lines(head)
+        } else {
+            head.lines().filter_map(parse_ass_line).collect()
+        };
+        info!("{} danmaku in {}", dms.len(), path);
+        for it in dms {
+            dtx.send(it).await?;
+        }
+        dtx.close();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml() {
+        let dms = bilivideo::parse_xml(
+            r#"<?xml version="1.0" encoding="UTF-8"?><i>
+            <d p="1.5,1,25,16711680,0,0,0,0">弹幕 one</d>
+            <d p="2">too short</d>
+            <d p="x,5,25,y">bad numbers</d>
+            <d>no p</d>
+            </i>"#,
+        )
+        .unwrap();
+        assert_eq!(dms.len(), 2);
+        assert_eq!(
+            (
+                dms[0].time,
+                dms[0].text.as_str(),
+                dms[0].color.as_str(),
+                dms[0].position
+            ),
+            (1500, "弹幕 one", "ff0000", 0)
+        );
+        assert_eq!(
+            (dms[1].time, dms[1].color.as_str(), dms[1].position),
+            (0, "ffffff", 8)
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let dms = parse_json_lines(
+            "{\"time\": 1700000000000, \"text\": \"第一\", \"nick\": \"某人\", \"color\": \"00ff00\"}\n\
+            not json\n\
+            {\"time\": 1700000002500, \"text\": \"second\", \"color\": \"bad\", \"position\": 8}\n\
+            {\"text\": \"no time\"}\n",
+        );
+        assert_eq!(dms.len(), 2);
+        assert_eq!(
+            (dms[0].time, dms[0].nick.as_str(), dms[0].color.as_str()),
+            (0, "某人", "00ff00")
+        );
+        assert_eq!(
+            (dms[1].time, dms[1].color.as_str(), dms[1].position),
+            (2500, "ffffff", 8)
+        );
+        let dms = parse_json_lines("{\"time\": 61000, \"text\": \"relative\"}\n{\"time\": 500, \"text\": \"earlier\"}");
+        assert_eq!((dms[0].time, dms[1].time), (61000, 500));
+    }
+
+    #[test]
+    fn ass() {
+        // Danmaku2ASS, colored and white, the latter with "c&" in a non-ascii text
+        let dm = parse_ass_line(
+            r"Dialogue: 2,0:01:02.50,0:01:10.50,R2L,,0000,0000,0000,,{\move(1920,25,-200,25)\c&H0000FF&}红色",
+        )
+        .unwrap();
+        assert_eq!(
+            (dm.time, dm.text.as_str(), dm.color.as_str(), dm.position),
+            (62500, "红色", "ff0000", 0)
+        );
+        let dm =
+            parse_ass_line(r"Dialogue: 2,0:00:01.00,0:00:09.00,R2L,,0000,0000,0000,,{\move(1920,25,-200,25)}abc&x弹幕")
+                .unwrap();
+        assert_eq!(
+            (dm.text.as_str(), dm.color.as_str()),
+            ("abc&x弹幕", "ffffff")
+        );
+        let dm = parse_ass_line(r"Dialogue: 2,0:00:01.00,0:00:09.00,R2L,,0000,0000,0000,,{\an8\fn微软雅黑}c&弹幕弹幕")
+            .unwrap();
+        assert_eq!((dm.color.as_str(), dm.position), ("ffffff", 8));
+        // dmlive's own bottom danmaku
+        let dm =
+            parse_ass_line(r"Dialogue: 0,0:00:03.00,0:00:11.00,Default,,0,0,0,,{\alpha00\fs40\1c&00ff00&\an2}bottom")
+                .unwrap();
+        assert_eq!((dm.color.as_str(), dm.position), ("00ff00", 2));
+        assert!(parse_ass_line(r"Dialogue: 0,0:00:00.00,0:00:01.00,Default,dmlive-empty,0,0,0,,").is_none());
+        assert!(parse_ass_line(r"Dialogue: 0,bad,0:00:01.00,Default,,0,0,0,,text").is_none());
+        assert!(parse_ass_line("Comment: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,text").is_none());
+    }
+}
@@ -1,13 +1,17 @@
 mod baha;
 mod bilibili;
 mod bilivideo;
+mod chatui;
 mod douyu;
 mod fudujikiller;
 mod huya;
+mod local;
 mod mkv_header;
 mod twitch;
+mod twitchvideo;
 mod youtube;
 
+use crate::config::Site;
 use crate::dmlive::DMLContext;
 use anyhow::Result;
 use chrono::{Duration, NaiveTime};
@@ -54,16 +58,32 @@ pub struct DMLDanmaku {
     position: u8,
 }
 
+impl DMLDanmaku {
+    /// The event of the danmaku feed.
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "nick": self.nick,
+            "text": self.text,
+            "color": self.color,
+            "superchat": self.text.starts_with("[SC]"),
+            "time": chrono::Utc::now().timestamp_millis(),
+        })
+        .to_string()
+    }
+}
+
 pub struct Danmaku {
     ctx: Rc<DMLContext>,
     show_nick: Cell<bool>,
     font_size: Cell<usize>,
     channel_num: Cell<usize>,
     ratio_scale: Cell<f64>,
+    stream_delay: Cell<i64>,
     read_order: Cell<usize>,
-    bili_video_cid: RefCell<String>,
+    video_id: RefCell<String>,
     dchannels: RefCell<Vec<DanmakuChannel>>,
     fk: fudujikiller::FudujiKiller,
+    ui: chatui::ChatUI,
 }
 
 impl Danmaku {
@@ -77,14 +97,16 @@ impl Danmaku {
             30
         ];
         Self {
+            ui: chatui::ChatUI::new(ctx.clone()),
             ctx,
             show_nick: Cell::new(false),
             font_size: Cell::new(font_size),
             channel_num: Cell::new((540.0 / font_size as f64).ceil() as usize),
             ratio_scale: Cell::new(1.0),
+            stream_delay: Cell::new(0),
             read_order: Cell::new(0),
             fk: fudujikiller::FudujiKiller::new(),
-            bili_video_cid: RefCell::new("".into()),
+            video_id: RefCell::new("".into()),
             dchannels: RefCell::new(ch),
         }
     }
@@ -124,10 +146,24 @@ impl Danmaku {
         self.ratio_scale.set(ratio_scale);
     }
 
-    pub async fn set_bili_video_cid(&self, cid: &str) {
-        let mut bvc = self.bili_video_cid.borrow_mut();
-        bvc.clear();
-        bvc.push_str(cid);
+    /// Sets how far the stream lags behind wall-clock time, so live danmaku land on the matching frames.
+    pub fn set_stream_delay(&self, delay: i64) {
+        self.stream_delay.set(delay.clamp(0, 20000));
+    }
+
+    pub async fn set_video_id(&self, id: &str) {
+        let mut vid = self.video_id.borrow_mut();
+        vid.clear();
+        vid.push_str(id);
+    }
+
+    /// Draws the terminal chat view until the program exits, see `--tui`.
+    pub async fn run_ui(&self) {
+        self.ui.run().await
+    }
+
+    pub fn restore_ui(&self) {
+        self.ui.restore();
     }
 
     pub async fn toggle_show_nick(&self) {
@@ -250,6 +286,7 @@ impl Danmaku {
     async fn launch_live_danmaku_task(&self, rx: async_channel::Receiver<DMLDanmaku>) -> Result<()> {
         let now = std::time::Instant::now();
         let padding_time = Cell::new(0);
+        let delay = (self.stream_delay.get() + self.ctx.cm.chat_offset).max(0);
         let mut socket = self.ctx.im.get_danmaku_socket().await?;
         let mut empty_dm = DMLDanmaku {
             text: "".to_string(),
@@ -262,13 +299,15 @@ impl Danmaku {
         socket.write_all(mkv_header::MKV_HEADER).await?;
         let t1 = async {
             while let Ok(mut dml_dm) = rx.recv().await {
+                self.ctx.cm.count_danmaku();
                 if !self.ctx.cm.quiet {
-                    println!("[{}] {}", &dml_dm.nick, &dml_dm.text);
+                    self.ui.push(&dml_dm);
                 }
                 if !self.fk.dm_check(&dml_dm.text) {
                     continue;
                 }
-                dml_dm.time = now.elapsed().as_millis() as i64 + padding_time.get();
+                self.ctx.im.get_danmaku_feed().publish(dml_dm.to_json());
+                dml_dm.time = now.elapsed().as_millis() as i64 + padding_time.get() + delay;
                 self.launch_single_danmaku(&dml_dm, &mkv_cluster, 1)?;
             }
             anyhow::Ok(())
@@ -285,7 +324,7 @@ impl Danmaku {
             }
             loop {
                 interval.tick().await;
-                let now_ts = now.elapsed().as_millis() as i64 + padding_time.get();
+                let now_ts = now.elapsed().as_millis() as i64 + padding_time.get() + delay;
                 empty_dm.time = now_ts;
                 self.launch_single_danmaku(&empty_dm, &mkv_cluster, 1)?;
                 self.launch_single_danmaku(&empty_dm, &mkv_cluster, 2)?;
@@ -358,44 +397,89 @@ impl Danmaku {
     }
 
     pub async fn danmaku_client_task(&self, dtx: async_channel::Sender<DMLDanmaku>) -> Result<()> {
+        self.client_task(self.ctx.cm.site, &self.ctx.cm.room_url, dtx).await
+    }
+
+    /// Runs the client of one room, tagged and recolored when the chats of several rooms are merged.
+    async fn tagged_client_task(&self, site: Site, url: &str, dtx: async_channel::Sender<DMLDanmaku>) -> Result<()> {
+        if self.ctx.cm.extra_chat.is_empty() {
+            return self.client_task(site, url, dtx).await;
+        }
+        let (tx, rx) = async_channel::unbounded::<DMLDanmaku>();
+        let tag = site.tag();
+        let color = self.ctx.cm.chat_colors.get(tag);
+        let relay = async {
+            while let Ok(mut dm) = rx.recv().await {
+                dm.text = match dm.text.strip_prefix("[SC]") {
+                    Some(it) => format!("[SC][{}] {}", tag, it),
+                    None => format!("[{}] {}", tag, &dm.text),
+                };
+                if let Some(c) = color
+                    && dm.position == 0
+                {
+                    dm.color = c.clone();
+                }
+                if dtx.send(dm).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let (it, _) = tokio::join!(self.client_task(site, url, tx), relay);
+        it
+    }
+
+    async fn client_task(&self, site: Site, url: &str, dtx: async_channel::Sender<DMLDanmaku>) -> Result<()> {
         loop {
-            match match self.ctx.cm.site {
-                crate::config::Site::BiliLive => {
+            match match site {
+                Site::BiliLive => {
                     let b = bilibili::Bilibili::new();
-                    b.run(&self.ctx.cm.room_url, dtx.clone()).await
+                    b.run(url, dtx.clone()).await
                 }
-                crate::config::Site::BiliVideo => {
+                Site::BiliVideo => {
                     let b = bilivideo::Bilibili::new();
                     b.run(
                         format!(
                             "http://api.bilibili.com/x/v1/dm/list.so?oid={}",
-                            self.bili_video_cid.borrow()
+                            self.video_id.borrow()
                         )
                         .as_str(),
                         dtx.clone(),
                     )
                     .await
                 }
-                crate::config::Site::BahaVideo => {
+                Site::BahaVideo => {
                     let b = baha::Baha::new();
-                    b.run(self.bili_video_cid.borrow().to_string(), dtx.clone()).await
+                    b.run(self.video_id.borrow().to_string(), dtx.clone()).await
                 }
-                crate::config::Site::DouyuLive => {
+                Site::DouyuLive => {
                     let b = douyu::Douyu::new();
-                    b.run(&self.ctx.cm.room_url, dtx.clone()).await
+                    b.run(url, dtx.clone()).await
                 }
-                crate::config::Site::HuyaLive => {
+                Site::HuyaLive => {
                     let b = huya::Huya::new();
-                    b.run(&self.ctx.cm.room_url, dtx.clone()).await
+                    b.run(url, dtx.clone()).await
                 }
-                crate::config::Site::TwitchLive => {
+                Site::TwitchLive => {
                     let b = twitch::Twitch::new();
-                    b.run(&self.ctx.cm.room_url, dtx.clone()).await
+                    b.run(url, dtx.clone()).await
                 }
-                crate::config::Site::YoutubeLive => {
+                Site::TwitchVideo => {
+                    let b = twitchvideo::Twitch::new();
+                    let vid = self.video_id.borrow().to_string();
+                    b.run(&vid, dtx.clone()).await
+                }
+                Site::YoutubeLive => {
                     let b = youtube::Youtube::new();
-                    b.run(&self.ctx.cm.room_url, dtx.clone()).await
+                    b.run(url, dtx.clone()).await
                 }
+                Site::Local => match self.ctx.cm.danmaku_file.as_ref() {
+                    Some(it) => local::Local::new().run(it, dtx.clone()).await,
+                    None => {
+                        // the danmaku of a dmlive recording are already in it
+                        dtx.close();
+                        Ok(())
+                    }
+                },
             } {
                 Ok(_) => {}
                 Err(e) => {
@@ -439,12 +523,46 @@ impl Danmaku {
         Ok(())
     }
 
+    pub async fn run_twitchvideo(&self, ratio_scale: f64) -> Result<()> {
+        info!("ratio: {}", &ratio_scale);
+        self.reset();
+        self.ratio_scale.set(ratio_scale);
+        let (dtx, drx) = async_channel::unbounded();
+        let (dc_res, fbd_res) = tokio::join!(
+            self.danmaku_client_task(dtx),
+            self.launch_video_danmaku_task(drx)
+        );
+        dc_res?;
+        fbd_res?;
+        info!("twitch video danmaku exited");
+        Ok(())
+    }
+
+    pub async fn run_local(&self, ratio_scale: f64) -> Result<()> {
+        info!("ratio: {}", &ratio_scale);
+        self.reset();
+        self.ratio_scale.set(ratio_scale);
+        let (dtx, drx) = async_channel::unbounded();
+        let (dc_res, fbd_res) = tokio::join!(
+            self.danmaku_client_task(dtx),
+            self.launch_video_danmaku_task(drx)
+        );
+        dc_res?;
+        fbd_res?;
+        info!("local danmaku exited");
+        Ok(())
+    }
+
     pub async fn run(&self) -> Result<()> {
         self.reset();
         let (dtx, drx) = async_channel::unbounded();
+        let extra_chat = futures::future::join_all(
+            self.ctx.cm.extra_chat.iter().map(|(site, url)| self.tagged_client_task(*site, url, dtx.clone())),
+        );
         tokio::select! {
-            it = self.danmaku_client_task(dtx) => { it?; },
+            it = self.tagged_client_task(self.ctx.cm.site, &self.ctx.cm.room_url, dtx.clone()) => { it?; },
             it = self.launch_live_danmaku_task(drx) => { it?; },
+            _ = extra_chat => {},
         }
         info!("danmaku exited");
         Ok(())
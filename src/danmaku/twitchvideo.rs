@@ -0,0 +1,83 @@
+use crate::dmlerr;
+use log::info;
+use serde_json::json;
+
+use super::DMLDanmaku;
+
+const TTV_API1: &str = "https://gql.twitch.tv/gql";
+const TTV_COMMENTS_HASH: &str = "b70a3591ff0f4e0313d126c6a1502d79a1c02baebb288227c582044aa76adf6a";
+
+pub struct Twitch {}
+
+impl Twitch {
+    pub fn new() -> Self {
+        Twitch {}
+    }
+
+    fn decode_comment(&self, node: &serde_json::Value) -> anyhow::Result<DMLDanmaku> {
+        let offset = node.pointer("/contentOffsetSeconds").and_then(|x| x.as_f64()).ok_or_else(|| dmlerr!())?;
+        let nick = node.pointer("/commenter/displayName").and_then(|x| x.as_str()).unwrap_or("").to_string();
+        let mut text = "".to_owned();
+        for f in node.pointer("/message/fragments").and_then(|x| x.as_array()).ok_or_else(|| dmlerr!())? {
+            text.push_str(f.pointer("/text").and_then(|x| x.as_str()).unwrap_or(""));
+        }
+        let color = node
+            .pointer("/message/userColor")
+            .and_then(|x| x.as_str())
+            .and_then(|x| x.strip_prefix("#"))
+            .filter(|x| x.len() == 6)
+            .unwrap_or("ffffff");
+        Ok(DMLDanmaku {
+            time: (offset * 1000.0) as i64,
+            text: text.trim().to_string(),
+            nick,
+            color: color.to_lowercase(),
+            position: 0,
+        })
+    }
+
+    pub async fn run(&self, vid: &str, dtx: async_channel::Sender<DMLDanmaku>) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::utils::gen_ua())
+            .connect_timeout(tokio::time::Duration::from_secs(10))
+            .build()?;
+        let mut cursor: Option<String> = None;
+        loop {
+            let variables = match cursor.as_ref() {
+                Some(c) => json!({ "videoID": vid, "cursor": c }),
+                None => json!({ "videoID": vid, "contentOffsetSeconds": 0 }),
+            };
+            let payload = json!([{
+                "operationName": "VideoCommentsByOffsetOrCursor",
+                "variables": variables,
+                "extensions": {
+                    "persistedQuery": { "version": 1, "sha256Hash": TTV_COMMENTS_HASH },
+                },
+            }]);
+            let resp = client
+                .post(TTV_API1)
+                .header("Referer", "https://www.twitch.tv/")
+                .header("Client-Id", "kimne78kx3ncx6brgo4mv6wki5h1ko")
+                .body(serde_json::to_vec(&payload)?)
+                .send()
+                .await?
+                .json::<serde_json::Value>()
+                .await?;
+            let comments = resp.pointer("/0/data/video/comments").ok_or_else(|| dmlerr!())?;
+            let edges = comments.pointer("/edges").and_then(|x| x.as_array()).ok_or_else(|| dmlerr!())?;
+            for node in edges.iter().filter_map(|e| e.pointer("/node")) {
+                if let Ok(it) = self.decode_comment(node) {
+                    dtx.send(it).await?;
+                }
+            }
+            let has_next = comments.pointer("/pageInfo/hasNextPage").and_then(|x| x.as_bool()).unwrap_or(false);
+            cursor = edges.last().and_then(|x| x.pointer("/cursor")).and_then(|x| x.as_str()).map(|x| x.to_string());
+            if !has_next || cursor.is_none() {
+                break;
+            }
+        }
+        info!("twitch video comments done: {}", vid);
+        dtx.close();
+        Ok(())
+    }
+}
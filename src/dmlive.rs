@@ -5,6 +5,7 @@ use crate::{
 use async_channel::{Receiver, Sender};
 use futures::StreamExt;
 use log::info;
+use std::collections::HashMap;
 use std::rc::Rc;
 use tokio::time::Duration;
 
@@ -27,6 +28,9 @@ pub enum DMLMessage {
     RequestRestart,
     RequestExit,
     StreamReady,
+    SetStreamDelay(i64),
+    StreamUnhealthy(String),
+    AddMark(String),
 }
 
 #[allow(unused)]
@@ -64,14 +68,46 @@ impl DMLive {
             _ = self.dispatch_task() => {},
             _ = self.mc.run() => {},
             _ = self.play() => {},
+            _ = self.serve_http() => {},
+            _ = self.serve_control() => {},
+            _ = self.serve_danmaku_feed() => {},
+            _ = self.dm.run_ui() => {},
             _ = signal_task => {},
         }
+        self.dm.restore_ui();
         match self.ctx.im.stop().await {
             Ok(_) => {}
             Err(err) => info!("ipc manager stop error: {err}"),
         };
     }
 
+    async fn serve_http(&self) {
+        match self.ctx.cm.http_address.as_ref() {
+            Some(it) => {
+                let _ = self.ctx.im.get_http_hub().serve(it).await.map_err(|e| info!("http server error: {}", e));
+            }
+            None => futures::future::pending::<()>().await,
+        }
+    }
+
+    async fn serve_danmaku_feed(&self) {
+        match self.ctx.cm.danmaku_feed.as_ref() {
+            Some(it) => {
+                let _ = self.ctx.im.get_danmaku_feed().serve(it).await.map_err(|e| info!("danmaku feed error: {}", e));
+            }
+            None => futures::future::pending::<()>().await,
+        }
+    }
+
+    async fn serve_control(&self) {
+        match self.ctx.cm.control.as_ref() {
+            Some(it) => {
+                let _ = self.ctx.im.get_control().serve(it).await.map_err(|e| info!("control socket error: {}", e));
+            }
+            None => futures::future::pending::<()>().await,
+        }
+    }
+
     async fn dispatch_task(&self) {
         let mut tasks = futures::stream::FuturesUnordered::new();
 
@@ -80,6 +116,11 @@ impl DMLive {
                 Some(_) = tasks.next() => {},
                 msg = self.ctx.mrx.recv() => {
                     match msg {
+                        Ok(DMLMessage::RequestExit) => {
+                            // ffmpeg gets to close the recording, the rest is torn down with the run loop
+                            let _ = self.fc.quit().await;
+                            return;
+                        },
                         Ok(it) => { tasks.push(self.dispatch(it)) },
                         Err(_) => { return; },
                     }
@@ -106,13 +147,17 @@ impl DMLive {
                 let _ = self.fc.quit().await;
             }
             DMLMessage::RequestExit => {
-                // self.quit().await;
+                // handled by dispatch_task
             }
             DMLMessage::SetVideoInfo((w, h, pts)) => {
                 info!("video info: w {w} h {h} pts {pts}");
                 // danmaku task
                 if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo) {
                     let _ = self.dm.run_bilivideo(16.0 * h as f64 / w as f64 / 9.0).await;
+                } else if matches!(self.ctx.cm.site, crate::config::Site::TwitchVideo) {
+                    let _ = self.dm.run_twitchvideo(16.0 * h as f64 / w as f64 / 9.0).await;
+                } else if matches!(self.ctx.cm.site, crate::config::Site::Local) {
+                    let _ = self.dm.run_local(16.0 * h as f64 / w as f64 / 9.0).await;
                 } else {
                     self.dm.set_ratio_scale((16.0 / 9.0) / (w as f64 / h as f64));
                     // let _ = self.dm.run(16.0 * h as f64 / w as f64 / 9.0, pts).await;
@@ -122,6 +167,18 @@ impl DMLive {
                 info!("stream ready");
                 let _ = self.dm.run().await;
             }
+            DMLMessage::SetStreamDelay(delay) => {
+                info!("stream delay: {delay}ms");
+                self.dm.set_stream_delay(delay);
+            }
+            DMLMessage::StreamUnhealthy(reason) => {
+                // a fresh url from the site usually brings the stream back in full quality
+                info!("re-resolve the stream: {reason}");
+                let _ = self.fc.quit().await;
+            }
+            DMLMessage::AddMark(label) => {
+                let _ = self.fc.add_mark(&label).await.map_err(|e| info!("add mark error: {}", e));
+            }
             DMLMessage::PlayVideo => {
                 let _ = self.play_video().await.map_err(|e| info!("play video error: {}", e));
             }
@@ -133,8 +190,30 @@ impl DMLive {
                         let _ = self.mc.reload_video().await;
                     }
                     crate::config::RunMode::Record => {
-                        if self.ctx.cm.http_address.is_none() {
-                            let _ = self.fc.write_record_task().await;
+                        if self.ctx.cm.http_address.is_none() && self.ctx.cm.restream.is_empty() {
+                            let _ = self.fc.write_record_task(false).await;
+                        } else {
+                            let hub = self.ctx.im.get_http_hub();
+                            let feed_task = async {
+                                let _ = hub
+                                    .feed(&self.ctx.im.get_f2m_socket_path())
+                                    .await
+                                    .map_err(|e| info!("http hub error: {}", e));
+                            };
+                            let hls_task = async {
+                                if self.ctx.cm.serve_hls {
+                                    let _ = self.fc.write_hls_task().await;
+                                }
+                            };
+                            let restream_task = futures::future::join_all(self.ctx.cm.restream.iter().map(|it| async {
+                                let _ = self.fc.restream_task(it).await.map_err(|e| info!("restream error: {}", e));
+                            }));
+                            let record_task = async {
+                                if self.ctx.cm.record || self.ctx.cm.render {
+                                    let _ = self.fc.write_record_task(true).await.map_err(|e| info!("record error: {}", e));
+                                }
+                            };
+                            let _ = tokio::join!(feed_task, hls_task, restream_task, record_task);
                         }
                     }
                 }
@@ -142,11 +221,31 @@ impl DMLive {
         }
     }
 
+    /// Plays or records until the end, the finished recordings are rendered alongside so that the next
+    /// live session is not missed; the renders in progress finish first.
     pub async fn play(&self) -> anyhow::Result<()> {
+        let (rtx, rrx) = async_channel::unbounded::<String>();
+        let play_task = async {
+            let ret = self.play_loop(&rtx).await;
+            rtx.close();
+            ret
+        };
+        let render_task = async {
+            while let Ok(it) = rrx.recv().await {
+                let _ = self.fc.render_task(&it).await.map_err(|e| info!("render error: {}", e));
+            }
+        };
+        tokio::join!(play_task, render_task).0
+    }
+
+    async fn play_loop(&self, render_tx: &async_channel::Sender<String>) -> anyhow::Result<()> {
         loop {
             match self.ctx.cm.run_mode {
                 crate::config::RunMode::Play => {
-                    if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo) {
+                    if matches!(
+                        self.ctx.cm.site,
+                        crate::config::Site::BiliVideo | crate::config::Site::TwitchVideo | crate::config::Site::Local
+                    ) {
                         self.play_video().await?;
                         tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
                     } else {
@@ -154,9 +253,19 @@ impl DMLive {
                     }
                 }
                 crate::config::RunMode::Record => match self.ctx.cm.record_mode {
+                    crate::config::RecordMode::All if self.ctx.cm.site == crate::config::Site::Local => {
+                        self.fc.render_task(&self.ctx.cm.room_url).await?;
+                        return Err(anyhow::anyhow!("render finished"));
+                    }
                     crate::config::RecordMode::All => {
                         self.play_live().await?;
-                        if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo) {
+                        if let Some(it) = self.fc.take_last_record().await {
+                            let _ = render_tx.send(it).await;
+                        }
+                        if matches!(
+                            self.ctx.cm.site,
+                            crate::config::Site::BiliVideo | crate::config::Site::TwitchVideo
+                        ) {
                             return Err(anyhow::anyhow!("recording finished"));
                         }
                     }
@@ -171,11 +280,32 @@ impl DMLive {
         // Ok(())
     }
 
+    /// Stands in for the stream finder when --video is given.
+    fn external_stream_info(url: &str) -> HashMap<&'static str, String> {
+        let title = match std::path::Path::new(url).file_stem() {
+            Some(it) if std::path::Path::new(url).is_file() => it.to_string_lossy().to_string(),
+            _ => url.trim_end_matches('/').rsplit('/').next().unwrap_or(url).to_string(),
+        };
+        HashMap::from([("url", url.to_string()), ("title", title)])
+    }
+
     pub async fn play_live(&self) -> anyhow::Result<()> {
-        let mut stream_info = self.sf.run().await?;
+        let mut stream_info = match self.ctx.cm.external_video.as_ref() {
+            Some(it) => Self::external_stream_info(it),
+            None => self.sf.run().await?,
+        };
+        self.st.sniff(&mut stream_info).await;
         self.ctx.cm.set_stream_type(&stream_info);
         *self.ctx.cm.title.borrow_mut() = stream_info.remove("title").unwrap();
-        self.dm.set_bili_video_cid(stream_info.get("bili_cid").unwrap_or(&"".to_string())).await;
+        self.dm.set_video_id(stream_info.get("video_id").unwrap_or(&"".to_string())).await;
+        if self.ctx.cm.direct_input.get() {
+            // nothing to stream, the danmaku start right away
+            let _ = self.ctx.mtx.send(DMLMessage::StreamReady).await;
+            self.fc.run(&stream_info).await?;
+            // a file or a vod does not come back like a live room
+            return Err(anyhow::anyhow!("the video has ended"));
+        }
+        self.st.prepare(&mut stream_info).await?;
         let ff_task = async {
             self.fc.run(&stream_info).await?;
             anyhow::Ok(())
@@ -185,7 +315,7 @@ impl DMLive {
             self.fc.quit().await?;
             anyhow::Ok(())
         };
-        if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo) {
+        if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo | crate::config::Site::TwitchVideo) {
             ff_task.await?;
         } else {
             let (_ff_res, _st_res) = tokio::join!(ff_task, streamer_task);
@@ -194,18 +324,30 @@ impl DMLive {
     }
 
     pub async fn play_video(&self) -> anyhow::Result<()> {
-        let mut stream_info = self.sf.run().await?;
+        let mut stream_info = match self.ctx.cm.site {
+            crate::config::Site::Local => Self::external_stream_info(&self.ctx.cm.room_url),
+            _ => self.sf.run().await?,
+        };
         self.ctx.cm.set_stream_type(&stream_info);
         *self.ctx.cm.title.borrow_mut() = stream_info.remove("title").unwrap();
-        self.dm.set_bili_video_cid(stream_info.get("bili_cid").unwrap_or(&"".to_string())).await;
-        self.mc.reload_edl_video(&stream_info).await?;
+        self.dm.set_video_id(stream_info.get("video_id").unwrap_or(&"".to_string())).await;
+        if let Some(it) = self.ctx.cm.external_video.as_ref() {
+            // the stream finder still knows the cid of the danmaku
+            self.mc.reload_url_video(it).await?;
+        } else if self.ctx.cm.audio_only && stream_info.contains_key("url_a") {
+            self.mc.reload_url_video(&stream_info["url_a"]).await?;
+        } else if stream_info.contains_key("url_v") {
+            self.mc.reload_edl_video(&stream_info).await?;
+        } else {
+            self.mc.reload_url_video(&stream_info["url"]).await?;
+        }
         Ok(())
     }
 
     pub async fn download_danmaku(&self) -> anyhow::Result<()> {
         let mut stream_info = self.sf.run().await?;
         *self.ctx.cm.title.borrow_mut() = stream_info.remove("title").unwrap();
-        self.dm.set_bili_video_cid(stream_info.get("bili_cid").unwrap_or(&"".to_string())).await;
+        self.dm.set_video_id(stream_info.get("video_id").unwrap_or(&"".to_string())).await;
         let ff_task = async {
             self.fc.write_danmaku_only_task().await?;
             anyhow::Ok(())
@@ -218,6 +360,9 @@ impl DMLive {
                 crate::config::Site::BahaVideo => {
                     let _ = self.dm.run_baha().await;
                 }
+                crate::config::Site::TwitchVideo => {
+                    let _ = self.dm.run_twitchvideo(1.0).await;
+                }
                 _ => todo!(),
             }
             anyhow::Ok(())
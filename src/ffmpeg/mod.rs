@@ -1,42 +1,74 @@
-use crate::config::{RunMode, Site, StreamType};
+use crate::config::config::{FfProgress, ProbeInfo};
+use crate::config::{Site, SiteType, StreamType};
+use crate::dmlerr;
 use crate::dmlive::DMLContext;
 use crate::dmlive::DMLMessage;
 use anyhow::Result;
 use anyhow::anyhow;
+use chrono::format::{Item, StrftimeItems};
 use log::info;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ops::Not;
 use std::rc::Rc;
-use tokio::io::{AsyncRead, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
 use tokio::process::ChildStdin;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt},
     process::Command,
 };
 
+/// Enough of the output of ffmpeg for ffprobe to see every track.
+const PROBE_HEAD_SIZE: usize = 1 << 20;
+
+/// Collects the start of the output of ffmpeg for the probe, the matroska header lists the tracks, so a second
+/// of it is enough even when a slow audio-only stream takes minutes to fill the buffer.
+struct ProbeHead {
+    tx: async_channel::Sender<Vec<u8>>,
+    data: Vec<u8>,
+    started: Option<std::time::Instant>,
+}
+
+impl ProbeHead {
+    fn new(tx: async_channel::Sender<Vec<u8>>) -> Self {
+        ProbeHead {
+            tx,
+            data: Vec::new(),
+            started: None,
+        }
+    }
+
+    async fn push(&mut self, data: &[u8]) {
+        if self.tx.is_closed() {
+            return;
+        }
+        self.data.extend_from_slice(data);
+        let started = *self.started.get_or_insert_with(std::time::Instant::now);
+        if data.is_empty() || self.data.len() >= PROBE_HEAD_SIZE || started.elapsed().as_secs() >= 1 {
+            let _ = self.tx.send(std::mem::take(&mut self.data)).await;
+            self.tx.close();
+        }
+    }
+}
+
 pub struct FfmpegControl {
     ctx: Rc<DMLContext>,
     ff_stdin: RefCell<Option<ChildStdin>>,
+    marks: RefCell<Option<(String, std::time::Instant)>>, // sidecar file and start of the recording
+    last_record: RefCell<Option<String>>,
 }
 impl FfmpegControl {
     pub fn new(ctx: Rc<DMLContext>) -> Self {
         Self {
             ctx,
             ff_stdin: RefCell::new(None),
+            marks: RefCell::new(None),
+            last_record: RefCell::new(None),
         }
     }
     pub async fn write_danmaku_only_task(&self) -> Result<()> {
         let in_stream = self.ctx.im.get_danmaku_socket_path();
-        let max_len = match self.ctx.cm.title.borrow().char_indices().nth(70) {
-            Some(it) => it.0,
-            None => self.ctx.cm.title.borrow().len(),
-        };
-        let now = chrono::Local::now();
-        let filename = format!(
-            "{} - {}.ass",
-            self.ctx.cm.title.borrow()[..max_len].replace('/', "-"),
-            now.format("%F %T")
-        );
+        let filename = self.ctx.cm.output_file_name("ass");
         let mut cmd = Command::new("ffmpeg");
         cmd.args(["-y", "-hide_banner", "-nostdin"]);
         cmd.arg("-i");
@@ -53,31 +85,338 @@ impl FfmpegControl {
         Ok(())
     }
 
-    pub async fn write_record_task(&self) -> Result<()> {
-        let in_stream = self.ctx.im.get_f2m_socket_path();
-        let max_len = match self.ctx.cm.title.borrow().char_indices().nth(70) {
-            Some(it) => it.0,
-            None => self.ctx.cm.title.borrow().len(),
+    /// Gives the probe up to five seconds, the output starts before it is done.
+    async fn wait_probe(&self) {
+        for _ in 0..50 {
+            if self.ctx.cm.probe_info.borrow().is_some() {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Records the output of ffmpeg, from the http hub when it is the one reading ffmpeg.
+    pub async fn write_record_task(&self, from_hub: bool) -> Result<()> {
+        let in_stream = match from_hub {
+            true => "-".to_string(),
+            false => self.ctx.im.get_f2m_socket_path(),
         };
-        let now = chrono::Local::now();
-        let filename = format!(
-            "{} - {}.mkv",
-            self.ctx.cm.title.borrow()[..max_len].replace('/', "-"),
-            now.format("%F %T")
-        );
+        if self.ctx.cm.audio_only {
+            // the container follows the audio codec, which the probe may not have told yet
+            self.wait_probe().await;
+        }
+        let audio_codec = self.ctx.cm.probe_info.borrow().as_ref().map(|it| it.audio_codec.clone());
+        let (ext, format) = match audio_codec.as_deref() {
+            _ if self.ctx.cm.audio_only.not() => ("mkv", "matroska"),
+            Some("opus") => ("opus", "opus"),
+            Some("aac") => ("m4a", "ipod"),
+            // matroska takes any codec
+            _ => ("mka", "matroska"),
+        };
+        let filename = self.ctx.cm.output_file_name(ext);
         let mut cmd = Command::new("ffmpeg");
         cmd.args(["-y", "-hide_banner", "-nostdin"]);
         cmd.arg("-i");
         cmd.arg(&in_stream);
-        cmd.args(["-c", "copy", "-f", "matroska"]);
+        if self.ctx.cm.chat_ui {
+            cmd.arg("-nostats");
+        }
+        if self.ctx.cm.audio_only {
+            cmd.args(["-map", "0:a:0"]);
+        }
+        cmd.args(["-c", "copy", "-f", format]);
         cmd.arg(&filename);
         let mut ff = cmd
-            .stdin(std::process::Stdio::null())
+            .stdin(match from_hub {
+                true => std::process::Stdio::piped(),
+                false => std::process::Stdio::null(),
+            })
             // .stderr(std::process::Stdio::null())
             .kill_on_drop(false)
-            .spawn()
-            .unwrap();
+            .spawn()?;
+        let marks = format!("{}.marks.txt", filename.trim_end_matches(&format!(".{ext}")));
+        *self.marks.borrow_mut() = Some((marks, std::time::Instant::now()));
+        if let Some(mut stdin) = ff.stdin.take() {
+            let _ = self.ctx.im.get_http_hub().pipe_to(&mut stdin, "record").await;
+        }
         let _ = ff.wait().await;
+        self.marks.borrow_mut().take();
+        *self.last_record.borrow_mut() = Some(filename);
+        Ok(())
+    }
+
+    async fn probe_duration(input: &str) -> Option<f64> {
+        let out = Command::new("ffprobe")
+            .args(["-v", "error", "-show_entries", "format=duration", "-of", "csv=p=0"])
+            .arg(input)
+            .output()
+            .await
+            .ok()?;
+        String::from_utf8_lossy(&out.stdout).trim().parse().ok()
+    }
+
+    /// Hardcodes the danmaku track of a recording into an x264 video next to it.
+    pub async fn render_task(&self, input: &str) -> Result<()> {
+        let output = format!(
+            "{}.render.mp4",
+            input.rsplit_once('.').map_or(input, |it| it.0)
+        );
+        // the subtitles filter takes a file name, a copy of the track spares escaping arbitrary titles
+        let ass = format!(
+            "/tmp/dml-{}-render.ass",
+            uuid::Uuid::new_v4().as_hyphenated()
+        );
+        let st = Command::new("ffmpeg")
+            .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+            .arg(input)
+            .args(["-map", "0:s:0", "-c", "copy"])
+            .arg(&ass)
+            .status()
+            .await?;
+        if st.success().not() {
+            return Err(anyhow!("no danmaku track in {}", input));
+        }
+        let duration = Self::probe_duration(input).await.unwrap_or(0.0);
+        let mut vf = format!("subtitles={}", &ass);
+        if self.ctx.cm.render_height > 0 {
+            // scale first, so that the danmaku are drawn at the output resolution
+            vf = format!("scale=-2:{},{}", self.ctx.cm.render_height, vf);
+        }
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args([
+            "-y",
+            "-hide_banner",
+            "-nostats",
+            "-loglevel",
+            "error",
+            "-progress",
+            "pipe:1",
+            "-i",
+        ]);
+        cmd.arg(input);
+        cmd.args(["-map", "0:v:0", "-map", "0:a:0?", "-vf", &vf]);
+        cmd.args(["-c:v", "libx264", "-preset", &self.ctx.cm.render_preset]);
+        cmd.args(["-crf", &self.ctx.cm.render_crf.to_string()]);
+        cmd.args(["-c:a", "copy", "-movflags", "+faststart"]);
+        cmd.arg(&output);
+        let mut ff = cmd.stdin(std::process::Stdio::null()).stdout(std::process::Stdio::piped()).spawn()?;
+        let mut reader = BufReader::new(ff.stdout.take().unwrap()).lines();
+        let (mut out_time, mut speed) = (0.0, String::new());
+        let mut reported = std::time::Instant::now();
+        while let Some(line) = reader.next_line().await? {
+            match line.split_once('=') {
+                Some(("out_time_us", v)) => out_time = v.parse::<f64>().unwrap_or(0.0) / 1000000.0,
+                Some(("speed", v)) => speed = v.trim().to_string(),
+                // it may take hours, a line every half a minute is enough
+                Some(("progress", _)) if reported.elapsed().as_secs() < 30 => {}
+                Some(("progress", _)) if duration > 0.0 => {
+                    info!(
+                        "render {}: {:.1}% speed {}",
+                        &output,
+                        (out_time / duration * 100.0).min(100.0),
+                        speed
+                    );
+                    reported = std::time::Instant::now();
+                }
+                Some(("progress", _)) => {
+                    info!("render {}: {:.0}s speed {}", &output, out_time, speed);
+                    reported = std::time::Instant::now();
+                }
+                _ => {}
+            }
+        }
+        let st = ff.wait().await?;
+        let _ = tokio::fs::remove_file(&ass).await;
+        if st.success().not() {
+            return Err(anyhow!("render {} failed", input));
+        }
+        info!("rendered {}", &output);
+        Ok(())
+    }
+
+    /// The recording that was just finished, if `--render` is set.
+    pub async fn take_last_record(&self) -> Option<String> {
+        if self.ctx.cm.render.not() || self.ctx.cm.audio_only {
+            return None;
+        }
+        // the recording ffmpeg finishes a moment after the stream ends
+        for _ in 0..50 {
+            if self.marks.borrow().is_none() && self.last_record.borrow().is_some() {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        }
+        let ret = self.last_record.borrow_mut().take();
+        if ret.is_none() {
+            info!("nothing was recorded to render");
+        }
+        ret
+    }
+
+    /// Appends the position in the running recording to its sidecar file.
+    pub async fn add_mark(&self, label: &str) -> Result<()> {
+        let (path, start) = self.marks.borrow().clone().ok_or(anyhow!("not recording"))?;
+        let ms = start.elapsed().as_millis();
+        let line = format!(
+            "{:02}:{:02}:{:02}.{:03}\t{}\n",
+            ms / 3600000,
+            ms / 60000 % 60,
+            ms / 1000 % 60,
+            ms % 1000,
+            label
+        );
+        info!("mark: {}", line.trim());
+        let mut f = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        f.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Renders the danmaku track of the mkv on stdin into the video, mpv's encoding mode burns subtitles in.
+    /// The x264 options are the ones of `--render`.
+    fn create_burn_command(&self, format: &str, ofopts: &[String], output: &str) -> Command {
+        let mut ret = Command::new("mpv");
+        ret.args(["--no-config", "--really-quiet", "--sid=1"]);
+        ret.arg("--ovc=libx264");
+        ret.arg(format!(
+            "--ovcopts=preset={},crf={}",
+            self.ctx.cm.render_preset, self.ctx.cm.render_crf
+        ));
+        ret.arg("--oac=aac");
+        ret.arg(format!("--of={format}"));
+        if ofopts.is_empty().not() {
+            ret.arg(format!("--ofopts={}", ofopts.join(",")));
+        }
+        ret.arg(format!("--o={output}"));
+        ret.arg("-");
+        ret
+    }
+
+    fn create_restream_command(&self, target: &str) -> Result<Command> {
+        let (format, output) = if target.starts_with("rtmp://") || target.starts_with("rtmps://") {
+            ("flv", target.to_string())
+        } else if target.starts_with("srt://") || target.starts_with("udp://") {
+            ("mpegts", target.to_string())
+        } else {
+            // a bad specifier makes chrono panic while formatting
+            if StrftimeItems::new(target).any(|it| it == Item::Error) {
+                return Err(anyhow!("invalid strftime pattern in {}", target));
+            }
+            let output = chrono::Local::now().format(target).to_string();
+            let format = match output.rsplit_once('.').map(|it| it.1) {
+                Some("mp4") => "mp4",
+                Some("ts") => "mpegts",
+                Some("flv") => "flv",
+                _ => "matroska",
+            };
+            (format, output)
+        };
+        if self.ctx.cm.burn_danmaku {
+            return Ok(self.create_burn_command(format, &[], &output));
+        }
+        let mut ret = Command::new("ffmpeg");
+        ret.args(["-y", "-hide_banner", "-nostats", "-i", "-"]);
+        ret.args(["-map", "0:v:0?", "-map", "0:a:0?"]);
+        // only matroska keeps the ass danmaku track
+        if format == "matroska" {
+            ret.args(["-map", "0:s?"]);
+        }
+        ret.args(["-c", "copy", "-f", format]);
+        if format == "mp4" {
+            ret.args(["-movflags", "+frag_keyframe+empty_moov"]);
+        }
+        ret.arg(output);
+        Ok(ret)
+    }
+
+    /// Relays the stream to one target, reconnecting on its own when the target drops.
+    pub async fn restream_task(&self, target: &str) -> Result<()> {
+        let mut backoff = 2;
+        loop {
+            let started = std::time::Instant::now();
+            let mut p = self
+                .create_restream_command(target)?
+                .stdin(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::null())
+                .kill_on_drop(true)
+                .spawn()?;
+            info!("restream to {}", target);
+            let mut stdin = p.stdin.take().unwrap();
+            let ret = self.ctx.im.get_http_hub().pipe_to(&mut stdin, target).await;
+            drop(stdin);
+            if ret.is_ok() {
+                // the source is done, the next one starts a new session
+                let _ = p.wait().await;
+                return Ok(());
+            }
+            let _ = p.kill().await;
+            if started.elapsed().as_secs() > 60 {
+                backoff = 2;
+            }
+            info!("restream to {} dropped, reconnect in {}s", target, backoff);
+            tokio::time::sleep(tokio::time::Duration::from_secs(backoff)).await;
+            backoff = (backoff * 2).min(60);
+        }
+    }
+
+    /// Remuxes to fMP4 HLS with the danmaku as WebVTT, or renders them into the video through mpv.
+    pub fn create_hls_command(&self, dir: &str) -> Command {
+        let opts = [
+            ("hls_time", "2"),
+            ("hls_list_size", "10"),
+            ("hls_flags", "delete_segments+independent_segments"),
+            ("hls_segment_type", "fmp4"),
+        ];
+        if self.ctx.cm.burn_danmaku {
+            let mut ofopts: Vec<_> = opts.iter().map(|(k, v)| format!("{k}={v}")).collect();
+            ofopts.push(format!("hls_segment_filename={dir}/seg_%05d.m4s"));
+            return self.create_burn_command("hls", &ofopts, &format!("{dir}/index.m3u8"));
+        }
+        let mut ret = Command::new("ffmpeg");
+        ret.args(["-y", "-hide_banner", "-nostats", "-i", "-"]);
+        // the variant lists every track, so only the ones that are there; without a probe, what map_args maps
+        let info = self.ctx.cm.probe_info.borrow().clone();
+        let mut streams = Vec::new();
+        if self.ctx.cm.audio_only.not() && info.as_ref().is_none_or(|it| it.video_codec.is_empty().not()) {
+            ret.args(["-map", "0:v:0"]);
+            streams.push("v:0");
+        }
+        if info.as_ref().is_none_or(|it| it.audio_codec.is_empty().not()) {
+            ret.args(["-map", "0:a:0"]);
+            streams.push("a:0");
+        }
+        // the danmaku track is always there, empty or not
+        ret.args(["-map", "0:s:0"]);
+        streams.push("s:0,sgroup:danmaku");
+        ret.args(["-c:v", "copy", "-c:a", "copy", "-c:s", "webvtt"]);
+        ret.args(["-f", "hls"]);
+        for (k, v) in opts {
+            ret.arg(format!("-{k}")).arg(v);
+        }
+        ret.args(["-master_pl_name", "index.m3u8"]);
+        ret.arg("-var_stream_map").arg(streams.join(","));
+        ret.args(["-hls_fmp4_init_filename", "init_%v.mp4"]);
+        ret.arg("-hls_segment_filename").arg(format!("{dir}/seg_%v_%05d.m4s"));
+        ret.arg(format!("{dir}/stream_%v.m3u8"));
+        ret
+    }
+
+    pub async fn write_hls_task(&self) -> Result<()> {
+        let dir = self.ctx.im.get_hls_dir();
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await?;
+        self.wait_probe().await;
+        let mut p = self
+            .create_hls_command(&dir)
+            .stdin(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = p.stdin.take().unwrap();
+        let _ = self.ctx.im.get_http_hub().pipe_to(&mut stdin, "hls").await;
+        drop(stdin);
+        let _ = p.wait().await;
+        // the segments of an ended stream are of no use to anyone
+        let _ = tokio::fs::remove_dir_all(&dir).await;
         Ok(())
     }
 
@@ -96,11 +435,24 @@ impl FfmpegControl {
         Ok(ret)
     }
 
+    /// Maps the video of input 0, the audio and the danmaku tracks of the given inputs; no video in audio-only mode.
+    fn map_args(&self, audio: usize, danmaku: usize) -> Vec<String> {
+        let mut ret = Vec::new();
+        if self.ctx.cm.audio_only.not() {
+            ret.extend(["-map".into(), "0:v:0?".into()]);
+        }
+        ret.extend(["-map".into(), format!("{audio}:a:0?")]);
+        ret.extend(["-map".into(), format!("{danmaku}:s:0")]);
+        ret.extend(["-map".into(), format!("{danmaku}:s:1?")]);
+        ret
+    }
+
     pub fn create_ff_command(&self, stream_info: &HashMap<&str, String>) -> Result<Command> {
         let mut ret = Command::new("ffmpeg");
         ret.args(["-y", "-xerror"]);
         ret.arg("-hide_banner");
         ret.arg("-nostats");
+        ret.args(["-progress", "pipe:1", "-stats_period", "1"]);
         // ret.args(["-use_wallclock_as_timestamps", "1"]);
         // ret.arg("-report");
         // ret.arg("-loglevel").arg("quiet");
@@ -108,38 +460,74 @@ impl FfmpegControl {
         // ret.args(["-analyzeduration", "1000000", "-max_delay", "1000000"]);
         // ret.args(["-max_delay", "1000000"]);
         match self.ctx.cm.stream_type.get() {
-            crate::config::StreamType::DASH => {
+            _ if self.ctx.cm.direct_input.get() => {
+                // a file or a vod, read at its own pace so that it keeps up with the live danmaku
+                ret.arg("-re");
+                ret.arg("-i").arg(&stream_info["url"]);
+                ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
+                ret.args(self.map_args(0, 1));
+            }
+            crate::config::StreamType::DASH | crate::config::StreamType::MPD => {
                 if self.ctx.cm.site == Site::BiliVideo {
-                    ret.args(&[
-                        "-user_agent",
-                        &crate::utils::gen_ua(),
-                        "-headers",
-                        "Referer: https://www.bilibili.com/",
-                    ]);
-                    ret.arg("-i").arg(&stream_info["url_v"]);
-                    ret.args(&[
+                    if self.ctx.cm.audio_only.not() {
+                        ret.args([
+                            "-user_agent",
+                            &crate::utils::gen_ua(),
+                            "-headers",
+                            "Referer: https://www.bilibili.com/",
+                        ]);
+                        ret.arg("-i").arg(&stream_info["url_v"]);
+                    }
+                    ret.args([
                         "-user_agent",
                         &crate::utils::gen_ua(),
                         "-headers",
                         "Referer: https://www.bilibili.com/",
                     ]);
                     ret.arg("-i").arg(&stream_info["url_a"]);
-                } else {
+                } else if self.ctx.cm.site == Site::TwitchVideo {
+                    ret.args(["-user_agent", &crate::utils::gen_ua()]);
+                    ret.arg("-i").arg(&stream_info["url"]);
+                    ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
+                    ret.args(self.map_args(0, 1));
+                } else if stream_info.contains_key("url_a") {
                     ret.arg("-i").arg(self.ctx.im.get_video_socket_path());
                     ret.arg("-i").arg(self.ctx.im.get_audio_socket_path());
+                } else {
+                    ret.arg("-i").arg(self.ctx.im.get_video_socket_path());
+                }
+                if self.ctx.cm.site != Site::TwitchVideo {
+                    ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
+                    if self.ctx.cm.site == Site::BiliVideo && self.ctx.cm.audio_only {
+                        ret.args(self.map_args(0, 1));
+                    } else if stream_info.contains_key("url_a") {
+                        ret.args(self.map_args(1, 2));
+                    } else {
+                        ret.args(self.map_args(0, 1));
+                    }
                 }
+            }
+            crate::config::StreamType::HLS(0) if stream_info.contains_key("url_a") => {
+                ret.arg("-i").arg("-");
+                ret.arg("-i").arg(self.ctx.im.get_audio_socket_path());
                 ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
-                ret.args(["-map", "0:v:0?", "-map", "1:a:0?", "-map", "2:s:0", "-map", "2:s:1?"]);
+                ret.args(self.map_args(1, 2));
             }
             crate::config::StreamType::HLS(0) => {
                 ret.arg("-i").arg("-");
                 ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
-                ret.args(["-map", "0:v:0?", "-map", "0:a:0?", "-map", "1:s:0", "-map", "1:s:1?"]);
+                ret.args(self.map_args(0, 1));
+            }
+            crate::config::StreamType::HLS(_) if stream_info.contains_key("url_a") => {
+                ret.arg("-i").arg(self.ctx.im.get_video_socket_path());
+                ret.arg("-i").arg(self.ctx.im.get_audio_socket_path());
+                ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
+                ret.args(self.map_args(1, 2));
             }
             _ => {
                 ret.arg("-i").arg(self.ctx.im.get_video_socket_path());
                 ret.arg("-i").arg(self.ctx.im.get_danmaku_socket_path());
-                ret.args(["-map", "0:v:0?", "-map", "0:a:0?", "-map", "1:s:0", "-map", "1:s:1?"]);
+                ret.args(self.map_args(0, 1));
             }
         }
         ret.args(&["-c:v", "copy"]);
@@ -153,21 +541,8 @@ impl FfmpegControl {
             "-f",
             "matroska",
         ]);
-        match self.ctx.cm.run_mode {
-            RunMode::Play => {
-                ret.arg("-listen").arg("1").arg(self.ctx.im.get_f2m_socket_path());
-            }
-            RunMode::Record => {
-                match self.ctx.cm.http_address.as_ref() {
-                    Some(it) => {
-                        ret.arg("-listen").arg("1").arg(it);
-                    }
-                    None => {
-                        ret.arg("-listen").arg("1").arg(self.ctx.im.get_f2m_socket_path());
-                    }
-                };
-            }
-        }
+        // passed on by output_task
+        ret.arg(self.ctx.im.get_f2m_in_socket_path());
         Ok(ret)
     }
 
@@ -183,72 +558,259 @@ impl FfmpegControl {
         Ok(())
     }
 
-    pub async fn get_video_info<T: AsyncRead + Unpin>(&self, ffstderr: T) -> Result<()> {
-        let mut reader = BufReader::new(ffstderr).lines();
-        let res_re = regex::Regex::new(r"Stream #[0-9].+? Video:.*?\D(\d{3,5})x(\d{2,5})\D.*").unwrap();
-        let pts_re = regex::Regex::new(r"Duration: ([^,\s]+),\s+(start: ([0-9.]+))*.+").unwrap();
-        let dm_re = regex::Regex::new(r"Stream #[0-9:]+\s*Subtitle:\s*ass").unwrap();
-        let mut vinfo_sent = false;
-        let mut ffready_sent = false;
-        let mut retry = 0;
-        // while let Some(line) = reader.next_line().await.unwrap_or(Some("".to_string())) {
-        while let Some(line) = reader.next_line().await.unwrap_or_else(|_| {
-            retry += 1;
-            if retry < 5 { Some("".to_string()) } else { None }
-        }) {
-            info!("{}", &line);
-            let line = line.trim();
-            if let Some(_it) = pts_re.captures(&line) {
-                // duration = utils::str_to_ms(&it[1]);
-                // let st: f64 = it.get(3).map_or("0", |it| it.as_str()).parse().unwrap_or(0.0);
-                // start = (st * 1000.0) as u64;
-                // continue;
-            } else if let Some(_it) = dm_re.captures(&line) {
-                if ffready_sent == false {
-                    let _ = self.ctx.mtx.send(DMLMessage::FfmpegOutputReady).await;
-                    ffready_sent = true;
+    /// Reads the streams of `ffprobe -show_entries stream=codec_type,codec_name,width,height,avg_frame_rate -of json`.
+    fn parse_probe(out: &[u8]) -> Result<ProbeInfo> {
+        let j: serde_json::Value = serde_json::from_slice(out)?;
+        let mut ret = ProbeInfo::default();
+        for st in j.pointer("/streams").and_then(|x| x.as_array()).ok_or_else(|| dmlerr!())? {
+            let codec = st.pointer("/codec_name").and_then(|x| x.as_str()).unwrap_or("").to_string();
+            match st.pointer("/codec_type").and_then(|x| x.as_str()) {
+                Some("video") if ret.width == 0 => {
+                    ret.video_codec = codec;
+                    ret.width = st.pointer("/width").and_then(|x| x.as_u64()).unwrap_or(0);
+                    ret.height = st.pointer("/height").and_then(|x| x.as_u64()).unwrap_or(0);
+                    ret.fps = st
+                        .pointer("/avg_frame_rate")
+                        .and_then(|x| x.as_str())
+                        .and_then(|x| x.split_once('/'))
+                        .and_then(|(n, d)| Some(n.parse::<f64>().ok()? / d.parse::<f64>().ok().filter(|d| *d > 0.0)?))
+                        .unwrap_or(0.0);
                 }
-            } else if let Some(it) = res_re.captures(&line) {
-                let w = it[1].parse().unwrap();
-                let h = it[2].parse().unwrap();
-                if w < 100 || h < 100 {
-                    let _ = self.quit().await;
+                Some("audio") if ret.audio_codec.is_empty() => ret.audio_codec = codec,
+                _ => {}
+            }
+        }
+        Ok(ret)
+    }
+
+    fn create_probe_command() -> Command {
+        let mut cmd = Command::new("ffprobe");
+        cmd.args(["-v", "error"]);
+        cmd.args([
+            "-show_entries",
+            "stream=codec_type,codec_name,width,height,avg_frame_rate",
+            "-of",
+            "json",
+        ]);
+        cmd
+    }
+
+    /// Probes the codecs and the resolution of a video or a --video, which ffmpeg reads on its own.
+    async fn probe(&self, stream_info: &HashMap<&str, String>) -> Result<ProbeInfo> {
+        let track = if self.ctx.cm.audio_only { "url_a" } else { "url_v" };
+        let url = stream_info.get(track).unwrap_or(&stream_info["url"]);
+        let referer = match self.ctx.cm.site {
+            Site::BiliVideo => "https://www.bilibili.com/",
+            _ => self.ctx.cm.room_url.as_str(),
+        };
+        let mut cmd = Self::create_probe_command();
+        // the http options fail on a local --video
+        if url.starts_with("http") {
+            cmd.args(["-rw_timeout", "10000000", "-user_agent", &crate::utils::gen_ua()]);
+            cmd.args(["-headers", &format!("Referer: {}\r\n", referer)]);
+        }
+        cmd.arg(url);
+        let out = tokio::time::timeout(
+            tokio::time::Duration::from_secs(20),
+            cmd.kill_on_drop(true).output(),
+        )
+        .await??;
+        Self::parse_probe(&out.stdout)
+    }
+
+    /// Probes the start of the output of ffmpeg, live urls are often good for one connection only.
+    async fn probe_head(&self, head: &[u8]) -> Result<ProbeInfo> {
+        let mut p = Self::create_probe_command()
+            .arg("-")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = p.stdin.take().unwrap();
+        // ffprobe may stop reading early
+        let _ = stdin.write_all(head).await;
+        drop(stdin);
+        let out = tokio::time::timeout(tokio::time::Duration::from_secs(20), p.wait_with_output()).await??;
+        Self::parse_probe(&out.stdout)
+    }
+
+    async fn probe_task(&self, stream_info: &HashMap<&str, String>, head_rx: async_channel::Receiver<Vec<u8>>) {
+        let info = if self.ctx.cm.site_type == SiteType::Live && self.ctx.cm.direct_input.get().not() {
+            match head_rx.recv().await {
+                Ok(it) => self.probe_head(&it).await,
+                Err(_) => return,
+            }
+        } else {
+            self.probe(stream_info).await
+        };
+        let info = match info {
+            Ok(it) => it,
+            Err(e) => {
+                info!("probe error: {}", e);
+                // the danmaku of videos only start with the video info, and ffmpeg waits for them
+                if matches!(self.ctx.cm.site, Site::BiliVideo | Site::TwitchVideo) {
+                    let _ = self.ctx.mtx.send(DMLMessage::SetVideoInfo((1920, 1080, 0))).await;
                 }
-                if vinfo_sent == false {
-                    let _ = self.ctx.mtx.send(DMLMessage::SetVideoInfo((w, h, 0))).await;
-                    vinfo_sent = true;
+                return;
+            }
+        };
+        info!(
+            "probe: {} {}x{} {:.2}fps, {}",
+            &info.video_codec, info.width, info.height, info.fps, &info.audio_codec
+        );
+        if info.width > 0 && info.height > 0 {
+            let _ = self.ctx.mtx.send(DMLMessage::SetVideoInfo((info.width, info.height, 0))).await;
+        } else if matches!(self.ctx.cm.site, Site::BiliVideo | Site::TwitchVideo) {
+            // an audio-only source, the danmaku still need a canvas
+            let _ = self.ctx.mtx.send(DMLMessage::SetVideoInfo((1920, 1080, 0))).await;
+        }
+        *self.ctx.cm.probe_info.borrow_mut() = Some(info);
+    }
+
+    /// Passes the output of ffmpeg on to the one reader of this run, and its start on to the probe. ffmpeg
+    /// connects once all of its inputs are open, which is when the reader can come; until it does, the
+    /// output is read ahead so that whoever waits for the probe does not hold up the reader in turn.
+    async fn output_task(&self, head_tx: async_channel::Sender<Vec<u8>>) -> Result<()> {
+        let mut ffout = self.ctx.im.get_f2m_in_socket().await?;
+        let _ = self.ctx.mtx.send(DMLMessage::FfmpegOutputReady).await;
+        let mut head = ProbeHead::new(head_tx);
+        let mut buf = vec![0u8; 65536];
+        let mut pending = Vec::new();
+        let mut eof = false;
+        let accept = self.ctx.im.get_f2m_socket();
+        tokio::pin!(accept);
+        let mut reader = loop {
+            tokio::select! {
+                it = &mut accept => {
+                    let mut reader = it?;
+                    reader.write_all(&pending).await?;
+                    break reader;
+                }
+                // ffmpeg waits for the reader once the read-ahead is full
+                it = ffout.read(&mut buf), if eof.not() && pending.len() < PROBE_HEAD_SIZE => {
+                    let n = it?;
+                    eof = n == 0;
+                    head.push(&buf[..n]).await;
+                    pending.extend_from_slice(&buf[..n]);
                 }
             }
+        };
+        while eof.not() {
+            let n = ffout.read(&mut buf).await?;
+            eof = n == 0;
+            head.push(&buf[..n]).await;
+            reader.write_all(&buf[..n]).await?;
         }
-        // warn!("get video info failed!");
-        // let _ = self.quit().await;
+        reader.shutdown().await?;
         Ok(())
     }
 
+    /// Reads the `-progress` key/value blocks.
+    /// Copies the flv of a pre ffmpeg tag by tag with the timestamps shifted by `base` ms, a restarted one starts
+    /// over from zero. The file header is dropped unless `header`. Returns the last timestamp written.
+    async fn copy_flv<R, W>(from: &mut R, to: &mut W, base: u32, header: bool) -> Result<u32>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut last = base;
+        let mut head = [0u8; 13];
+        match from.read_exact(&mut head).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(last),
+            Err(e) => return Err(e.into()),
+        }
+        if header {
+            to.write_all(&head).await?;
+        }
+        let mut body = Vec::new();
+        loop {
+            let mut tag = [0u8; 11];
+            match from.read_exact(&mut tag).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let size = u32::from_be_bytes([0, tag[1], tag[2], tag[3]]) as usize;
+            let ts = u32::from_be_bytes([tag[7], tag[4], tag[5], tag[6]]).wrapping_add(base);
+            tag[4..7].copy_from_slice(&ts.to_be_bytes()[1..]);
+            tag[7] = (ts >> 24) as u8;
+            last = last.max(ts);
+            // the data and the size of the previous tag
+            body.resize(size + 4, 0);
+            from.read_exact(&mut body).await?;
+            to.write_all(&tag).await?;
+            to.write_all(&body).await?;
+        }
+        Ok(last)
+    }
+
+    async fn progress_task<T: AsyncRead + Unpin>(&self, ffstdout: T) {
+        let mut reader = BufReader::new(ffstdout).lines();
+        let mut p = FfProgress::default();
+        while let Ok(Some(line)) = reader.next_line().await {
+            let (k, v) = match line.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => continue,
+            };
+            match k {
+                "fps" => p.fps = v.parse().unwrap_or(0.0),
+                "bitrate" => p.bitrate = v.trim_end_matches("kbits/s").parse().unwrap_or(0.0),
+                "total_size" => p.total_size = v.parse().unwrap_or(0),
+                "out_time_us" => p.out_time = v.parse::<f64>().unwrap_or(0.0) / 1000000.0,
+                "speed" => p.speed = v.trim_end_matches('x').parse().unwrap_or(0.0),
+                "progress" => *self.ctx.cm.ff_progress.borrow_mut() = p.clone(),
+                _ => {}
+            }
+        }
+    }
+
+    async fn log_task<T: AsyncRead + Unpin>(&self, ffstderr: T) {
+        let mut reader = BufReader::new(ffstderr).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            info!("{}", &line);
+        }
+    }
+
     pub async fn run(&self, stream_info: &HashMap<&str, String>) -> Result<()> {
-        let mut ff = self
-            .create_ff_command(stream_info)?
+        let mut cmd = self.create_ff_command(stream_info)?;
+        let mut ff = cmd
             .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped())
             .kill_on_drop(true)
             .spawn()
             .unwrap();
         let ffstderr = ff.stderr.take().unwrap();
+        let ffstdout = ff.stdout.take().unwrap();
+        *self.ctx.cm.ff_progress.borrow_mut() = FfProgress::default();
+        self.ctx.cm.probe_info.borrow_mut().take();
+        self.ctx.cm.restart_pre_ff.set(false);
         let ff_task = async {
             if self.ctx.cm.stream_type.get() == StreamType::HLS(0) {
-                let mut preff = self
-                    .create_pre_ff_command()?
-                    .stdin(std::process::Stdio::piped())
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::null())
-                    .kill_on_drop(false)
-                    .spawn()
-                    .unwrap();
-                let ffstdin = preff.stdin.take().unwrap();
-                *self.ff_stdin.borrow_mut() = Some(ffstdin);
                 let mut ffin = ff.stdin.take().unwrap();
-                let mut preffout = preff.stdout.take().unwrap();
-                tokio::io::copy(&mut preffout, &mut ffin).await?;
+                let mut base = None;
+                loop {
+                    let mut preff = self
+                        .create_pre_ff_command()?
+                        .stdin(std::process::Stdio::piped())
+                        .stdout(std::process::Stdio::piped())
+                        .stderr(std::process::Stdio::null())
+                        .kill_on_drop(false)
+                        .spawn()
+                        .unwrap();
+                    let ffstdin = preff.stdin.take().unwrap();
+                    *self.ff_stdin.borrow_mut() = Some(ffstdin);
+                    let mut preffout = preff.stdout.take().unwrap();
+                    // a restarted pre ffmpeg goes on a frame after the last one, as one flv stream
+                    let last = Self::copy_flv(&mut preffout, &mut ffin, base.unwrap_or(0), base.is_none()).await?;
+                    base = Some(last.saturating_add(40));
+                    let _ = preff.wait().await;
+                    if self.ctx.cm.restart_pre_ff.replace(false).not() {
+                        break;
+                    }
+                    info!("restart pre ffmpeg");
+                }
                 ff.kill().await?;
                 ff.wait().await?;
             } else {
@@ -259,7 +821,73 @@ impl FfmpegControl {
             anyhow::Ok(())
         };
 
-        let _ = tokio::join!(ff_task, self.get_video_info(ffstderr));
+        let (head_tx, head_rx) = async_channel::bounded(1);
+        let output_task = async {
+            let _ = self.output_task(head_tx).await.map_err(|e| info!("ffmpeg output error: {}", e));
+        };
+        let ff_task = async {
+            tokio::pin!(ff_task, output_task);
+            tokio::select! {
+                // the last bytes may still be on their way to the reader
+                it = &mut ff_task => {
+                    let _ = tokio::time::timeout(tokio::time::Duration::from_secs(5), &mut output_task).await;
+                    it
+                },
+                _ = &mut output_task => ff_task.await,
+            }
+        };
+        let _ = tokio::join!(
+            ff_task,
+            self.log_task(ffstderr),
+            self.progress_task(ffstdout),
+            self.probe_task(stream_info, head_rx)
+        );
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_json() {
+        let out = br#"{
+            "programs": [],
+            "streams": [
+                { "codec_name": "h264", "codec_type": "video", "width": 1920, "height": 1080, "avg_frame_rate": "60000/1001" },
+                { "codec_name": "aac", "codec_type": "audio", "avg_frame_rate": "0/0" },
+                { "codec_name": "hevc", "codec_type": "video", "width": 640, "height": 360, "avg_frame_rate": "30/1" }
+            ]
+        }"#;
+        let info = FfmpegControl::parse_probe(out).unwrap();
+        assert_eq!(info.video_codec, "h264");
+        assert_eq!((info.width, info.height), (1920, 1080));
+        assert!((info.fps - 59.94).abs() < 0.01);
+        assert_eq!(info.audio_codec, "aac");
+
+        let info = FfmpegControl::parse_probe(br#"{"streams":[{"codec_name":"opus","codec_type":"audio"}]}"#).unwrap();
+        assert_eq!((info.width, info.height, info.fps), (0, 0, 0.0));
+        assert_eq!(info.audio_codec, "opus");
+        assert!(FfmpegControl::parse_probe(b"").is_err());
+    }
+
+    #[tokio::test]
+    async fn flv_rebase() {
+        let tag = |ts: u32| {
+            let mut it = vec![9, 0, 0, 2];
+            it.extend_from_slice(&ts.to_be_bytes()[1..]);
+            it.extend_from_slice(&[(ts >> 24) as u8, 0, 0, 0, 0xaa, 0xbb, 0, 0, 0, 13]);
+            it
+        };
+        let mut flv = b"FLV\x01\x05\0\0\0\x09\0\0\0\0".to_vec();
+        flv.extend(tag(0));
+        flv.extend(tag(0x00ff_fff0));
+        let mut out = Vec::new();
+        let last = FfmpegControl::copy_flv(&mut flv.as_slice(), &mut out, 0x20, false).await.unwrap();
+        assert_eq!(last, 0x0100_0010);
+        let mut expected = tag(0x20);
+        expected.extend(tag(0x0100_0010));
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    config::{ConfigManager, RunMode, Site},
+    dmlive::DMLMessage,
+};
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use log::info;
+use serde_json::{Value, json};
+use std::{ops::Not, rc::Rc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+/// Maps JSON commands like `{"cmd": "speed", "value": 9000}` onto `DMLMessage`, for the control socket and
+/// `POST /control` from localhost.
+pub struct Control {
+    cm: Rc<ConfigManager>,
+    mtx: async_channel::Sender<DMLMessage>,
+}
+
+impl Control {
+    pub fn new(cm: Rc<ConfigManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
+        Self { cm, mtx }
+    }
+
+    fn status(&self) -> Value {
+        let p = self.cm.ff_progress.borrow();
+        json!({
+            "ok": true,
+            "title": *self.cm.title.borrow(),
+            "url": self.cm.room_url,
+            "site": format!("{:?}", self.cm.site),
+            "run_mode": match self.cm.run_mode {
+                RunMode::Play => "play",
+                RunMode::Record => "record",
+            },
+            "uptime": self.cm.started.elapsed().as_secs(),
+            "bytes": p.total_size,
+            "bitrate": p.bitrate,
+            "danmaku_rate": self.cm.danmaku_rate(),
+        })
+    }
+
+    async fn send(&self, msg: DMLMessage) -> Result<()> {
+        self.mtx.send(msg).await.map_err(|_| anyhow!("dmlive is exiting"))
+    }
+
+    async fn run_command(&self, j: &Value) -> Result<Value> {
+        let cmd = j.pointer("/cmd").and_then(|x| x.as_str()).ok_or_else(|| anyhow!("missing cmd"))?;
+        let value = j.pointer("/value");
+        let number = || value.and_then(|x| x.as_f64()).ok_or_else(|| anyhow!("{} needs a numeric value", cmd));
+        let is_video = matches!(self.cm.site, Site::BiliVideo | Site::TwitchVideo);
+        match cmd {
+            "status" => return Ok(self.status()),
+            "font_scale" => self.send(DMLMessage::SetFontScale(number()?)).await?,
+            "font_alpha" => self.send(DMLMessage::SetFontAlpha(number()?)).await?,
+            "speed" => self.send(DMLMessage::SetDMSpeed(number()? as u64)).await?,
+            "nick" => self.send(DMLMessage::ToggleShowNick).await?,
+            "reload" if is_video => self.send(DMLMessage::PlayVideo).await?,
+            "reload" => self.send(DMLMessage::RequestRestart).await?,
+            "page" | "next" | "back" if is_video.not() => return Err(anyhow!("{} is for videos only", cmd)),
+            "page" => {
+                self.cm.bvideo_info.borrow_mut().current_page = number()? as usize;
+                self.send(DMLMessage::PlayVideo).await?;
+            }
+            "next" => {
+                self.cm.bvideo_info.borrow_mut().current_page += 1;
+                self.send(DMLMessage::PlayVideo).await?;
+            }
+            "back" => {
+                let p = self.cm.bvideo_info.borrow().current_page.saturating_sub(1);
+                self.cm.bvideo_info.borrow_mut().current_page = p.max(1);
+                self.send(DMLMessage::PlayVideo).await?;
+            }
+            "mark" => {
+                let label = value.and_then(|x| x.as_str()).unwrap_or("");
+                self.send(DMLMessage::AddMark(label.to_string())).await?;
+            }
+            "stop" => self.send(DMLMessage::RequestExit).await?,
+            _ => return Err(anyhow!("unknown cmd {}", cmd)),
+        }
+        Ok(json!({ "ok": true }))
+    }
+
+    /// Runs one JSON command and returns the JSON reply.
+    pub async fn handle(&self, req: &str) -> String {
+        let ret = match serde_json::from_str::<Value>(req) {
+            Ok(j) => self.run_command(&j).await,
+            Err(e) => Err(e.into()),
+        };
+        match ret {
+            Ok(it) => it.to_string(),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }).to_string(),
+        }
+    }
+
+    async fn handle_client(&self, s: UnixStream) -> Result<()> {
+        let (r, mut w) = s.into_split();
+        let mut lines = BufReader::new(r).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut reply = self.handle(&line).await;
+            reply.push('\n');
+            w.write_all(reply.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Serves newline separated JSON commands on a unix socket, one reply line per command.
+    pub async fn serve(&self, path: &str) -> Result<()> {
+        let _ = tokio::fs::remove_file(path).await;
+        let listener = UnixListener::bind(path)?;
+        info!("control socket listening on {}", path);
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (s, _) = it?;
+                    tasks.push(async move {
+                        if let Err(e) = self.handle_client(s).await {
+                            info!("control client error: {}", e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
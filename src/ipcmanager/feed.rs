@@ -0,0 +1,134 @@
+use super::http::{HttpHub, bind_address};
+use crate::config::ConfigManager;
+use anyhow::Result;
+use futures::StreamExt;
+use log::info;
+use std::{ops::Not, rc::Rc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+
+/// A browser source for OBS. Query parameters: max (lines on screen), nick=0, sc=0 (hide superchats),
+/// block=word1,word2 and size (font size in px).
+const OVERLAY: &str = r##"<!DOCTYPE html><html><head><meta charset="utf-8"><title>dmlive overlay</title>
+<style>
+html, body { margin: 0; background: transparent; overflow: hidden; }
+#chat { position: absolute; left: 8px; right: 8px; bottom: 8px; font-family: sans-serif; font-weight: bold; }
+.dm { margin-top: 4px; transition: opacity 1s; text-shadow: -1px -1px 0 #000, 1px -1px 0 #000, -1px 1px 0 #000, 1px 1px 0 #000; }
+.nick { opacity: 0.8; margin-right: 0.4em; }
+.sc { color: #000 !important; text-shadow: none; border-radius: 6px; padding: 4px 8px; }
+</style></head>
+<body><div id="chat"></div>
+<script>
+const q = new URLSearchParams(location.search);
+const max = parseInt(q.get("max") || "20");
+const block = (q.get("block") || "").split(",").map(s => s.trim()).filter(s => s);
+const chat = document.getElementById("chat");
+let cfg = { font_scale: 1, font_alpha: 0, speed: 8000 };
+function add(d) {
+  if (d.superchat && q.get("sc") === "0") return;
+  if (block.some(w => d.text.includes(w) || d.nick.includes(w))) return;
+  const el = document.createElement("div");
+  el.className = d.superchat ? "dm sc" : "dm";
+  el.style.fontSize = (q.get("size") || 28 * cfg.font_scale) + "px";
+  el.style.opacity = 1 - cfg.font_alpha;
+  if (d.superchat) el.style.background = "#" + d.color; else el.style.color = "#" + d.color;
+  if (q.get("nick") !== "0") {
+    const n = document.createElement("span");
+    n.className = "nick";
+    n.textContent = d.nick + ":";
+    el.appendChild(n);
+  }
+  el.appendChild(document.createTextNode(d.text));
+  chat.appendChild(el);
+  while (chat.children.length > max) chat.firstChild.remove();
+  setTimeout(() => { el.style.opacity = 0; setTimeout(() => el.remove(), 1000); }, cfg.speed * 2);
+}
+const es = new EventSource("/events");
+es.addEventListener("config", e => { cfg = JSON.parse(e.data); });
+es.onmessage = e => add(JSON.parse(e.data));
+</script></body></html>"##;
+
+/// Publishes the live danmaku as server-sent events, with an overlay page for OBS browser sources.
+pub struct DanmakuFeed {
+    tx: broadcast::Sender<String>,
+    cm: Rc<ConfigManager>,
+}
+
+impl DanmakuFeed {
+    pub fn new(cm: Rc<ConfigManager>) -> Self {
+        let (tx, _) = broadcast::channel(256);
+        Self { tx, cm }
+    }
+
+    /// Sends one danmaku, as JSON, to every connected client.
+    pub fn publish(&self, json: String) {
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(json);
+        }
+    }
+
+    /// Answers /overlay and /events, also on the --http-address server.
+    pub async fn handle(&self, s: &mut TcpStream, path: &str) -> Result<()> {
+        if path.starts_with("/events").not() {
+            return HttpHub::respond(s, "text/html; charset=utf-8", OVERLAY.as_bytes()).await;
+        }
+        let mut rx = self.tx.subscribe();
+        s.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n")
+            .await?;
+        // the overlay follows the font settings of the player
+        let config = serde_json::json!({
+            "font_scale": self.cm.font_scale.get(),
+            "font_alpha": self.cm.font_alpha.get(),
+            "speed": self.cm.danmaku_speed.get(),
+        });
+        s.write_all(format!("event: config\ndata: {}\n\n", config).as_bytes()).await?;
+        loop {
+            let event = match tokio::time::timeout(tokio::time::Duration::from_secs(15), rx.recv()).await {
+                Ok(Ok(it)) => format!("data: {}\n\n", it),
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) => return Ok(()),
+                // keeps proxies from closing an idle connection
+                Err(_) => ": ping\n\n".to_string(),
+            };
+            s.write_all(event.as_bytes()).await?;
+        }
+    }
+
+    async fn handle_client(&self, mut s: TcpStream) -> Result<()> {
+        let mut req = Vec::new();
+        let mut buf = [0u8; 1024];
+        while req.windows(4).any(|it| it == b"\r\n\r\n").not() {
+            let n = tokio::time::timeout(tokio::time::Duration::from_secs(10), s.read(&mut buf)).await??;
+            if n == 0 || req.len() > 8192 {
+                return Ok(());
+            }
+            req.extend_from_slice(&buf[..n]);
+        }
+        let req = String::from_utf8_lossy(&req);
+        let path = req.lines().next().unwrap_or("").split_whitespace().nth(1).unwrap_or("/").to_string();
+        self.handle(&mut s, &path).await
+    }
+
+    pub async fn serve(&self, address: &str) -> Result<()> {
+        let address = bind_address(address);
+        let listener = TcpListener::bind(&address).await?;
+        info!("danmaku feed on http://{}/overlay", &address);
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (s, addr) = it?;
+                    tasks.push(async move {
+                        if let Err(e) = self.handle_client(s).await {
+                            info!("danmaku feed client {} error: {}", addr, e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use super::{control::Control, feed::DanmakuFeed};
+use crate::config::ConfigManager;
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::StreamExt;
+use log::{info, warn};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    net::SocketAddr,
+    ops::Not,
+    rc::Rc,
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+
+/// Takes "host:port" or "http://host:port/" as it was given to ffmpeg before.
+pub(super) fn bind_address(address: &str) -> String {
+    match url::Url::parse(address) {
+        Ok(it) if it.host_str().is_some() => {
+            format!(
+                "{}:{}",
+                it.host_str().unwrap(),
+                it.port_or_known_default().unwrap_or(80)
+            )
+        }
+        _ => address.to_string(),
+    }
+}
+
+const EBML_ID: u64 = 0x1a45dfa3;
+const SEGMENT_ID: u64 = 0x18538067;
+const CLUSTER_ID: u64 = 0x1f43b675;
+
+/// Plays /hls/index.m3u8 natively (Safari, phones), or through hls.js when the browser can reach its CDN; without
+/// either it points to the playlist for an external player.
+const HLS_PLAYER: &str = r#"<!DOCTYPE html><html><head><meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1"><title>dmlive</title>
+<style>body { margin: 0; background: #000; color: #ccc; font-family: sans-serif; } video { width: 100vw; height: 100vh; }
+#hint { position: absolute; top: 8px; left: 8px; display: none; } a { color: #8cf; }</style></head>
+<body><video id="v" controls autoplay muted playsinline></video>
+<div id="hint">This browser can not play HLS by itself. Open <a href="index.m3u8">index.m3u8</a> in a player,
+or the <a href="/">mkv stream</a>.</div>
+<script>
+const v = document.getElementById("v");
+const hint = () => { document.getElementById("hint").style.display = "block"; };
+if (v.canPlayType("application/vnd.apple.mpegurl")) {
+  v.src = "index.m3u8";
+} else {
+  // offline or behind a firewall the script does not load
+  const s = document.createElement("script");
+  s.src = "https://cdn.jsdelivr.net/npm/hls.js@1";
+  s.onload = () => {
+    if (!Hls.isSupported()) return hint();
+    const hls = new Hls({ renderTextTracksNatively: true });
+    hls.loadSource("index.m3u8");
+    hls.attachMedia(v);
+    hls.on(Hls.Events.SUBTITLE_TRACKS_UPDATED, () => { hls.subtitleTrack = 0; });
+  };
+  s.onerror = hint;
+  document.head.appendChild(s);
+}
+</script></body></html>
+"#;
+
+/// Reads an EBML variable size integer, the marker bit is kept for element ids.
+fn read_vint(buf: &[u8], keep_marker: bool) -> Option<(u64, usize, bool)> {
+    let first = *buf.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || buf.len() < len {
+        return None;
+    }
+    let mut v = if keep_marker {
+        first as u64
+    } else {
+        (first as u64) & (0xff >> len)
+    };
+    let mut unknown = v == (0xff >> len);
+    for b in &buf[1..len] {
+        v = (v << 8) | *b as u64;
+        unknown &= *b == 0xff;
+    }
+    Some((v, len, unknown))
+}
+
+struct Client {
+    since: Instant,
+    sent: Cell<u64>,
+}
+
+/// Reads the muxed mkv from ffmpeg once and fans it out to any number of http clients.
+pub struct HttpHub {
+    header: RefCell<Option<Bytes>>,
+    cluster: RefCell<Option<Bytes>>, // the latest one, so that late joiners start right away
+    tx: broadcast::Sender<Bytes>,    // an empty chunk ends the stream
+    clients: RefCell<HashMap<String, Rc<Client>>>,
+    hls_dir: String,
+    source_since: Cell<Option<Instant>>,
+    control: Rc<Control>,
+    feed: Rc<DanmakuFeed>,
+    cm: Rc<ConfigManager>,
+}
+
+impl HttpHub {
+    pub fn new(cm: Rc<ConfigManager>, hls_dir: String, control: Rc<Control>, feed: Rc<DanmakuFeed>) -> Self {
+        let (tx, _) = broadcast::channel(64);
+        Self {
+            header: RefCell::new(None),
+            cluster: RefCell::new(None),
+            tx,
+            clients: RefCell::new(HashMap::new()),
+            hls_dir,
+            source_since: Cell::new(None),
+            control,
+            feed,
+            cm,
+        }
+    }
+
+    pub async fn serve(&self, address: &str) -> Result<()> {
+        let address = bind_address(address);
+        let listener = TcpListener::bind(&address).await?;
+        info!("http server listening on {}", &address);
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (s, addr) = it?;
+                    tasks.push(async move {
+                        if let Err(e) = self.handle_client(s, addr).await {
+                            info!("http client {} error: {}", addr, e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    async fn handle_client(&self, mut s: TcpStream, addr: SocketAddr) -> Result<()> {
+        let mut req = Vec::new();
+        let mut buf = [0u8; 1024];
+        while req.windows(4).any(|it| it == b"\r\n\r\n").not() {
+            let n = tokio::time::timeout(tokio::time::Duration::from_secs(10), s.read(&mut buf)).await??;
+            if n == 0 || req.len() > 8192 {
+                return Ok(());
+            }
+            req.extend_from_slice(&buf[..n]);
+        }
+        let head_len = req.windows(4).position(|it| it == b"\r\n\r\n").unwrap_or(0) + 4;
+        let mut body = req.split_off(head_len);
+        let req = String::from_utf8_lossy(&req);
+        let mut line = req.lines().next().unwrap_or("").split_whitespace();
+        let method = line.next().unwrap_or("");
+        let path = line.next().unwrap_or("/");
+        if path == "/control" {
+            // the stream is for the LAN, the commands only for this machine; the status stays open
+            if method == "POST" && addr.ip().is_loopback().not() {
+                s.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+                return Ok(());
+            }
+            let reply = if method == "POST" {
+                let len = req
+                    .lines()
+                    .filter_map(|it| it.split_once(':'))
+                    .find(|it| it.0.eq_ignore_ascii_case("content-length"))
+                    .and_then(|it| it.1.trim().parse::<usize>().ok())
+                    .unwrap_or(0)
+                    .min(65536);
+                while body.len() < len {
+                    let n = tokio::time::timeout(tokio::time::Duration::from_secs(10), s.read(&mut buf)).await??;
+                    if n == 0 {
+                        break;
+                    }
+                    body.extend_from_slice(&buf[..n]);
+                }
+                self.control.handle(&String::from_utf8_lossy(&body)).await
+            } else {
+                self.control.handle(r#"{"cmd": "status"}"#).await
+            };
+            return Self::respond(&mut s, "application/json", reply.as_bytes()).await;
+        }
+        if path == "/status" {
+            return Self::respond(
+                &mut s,
+                "text/html; charset=utf-8",
+                self.status_page().as_bytes(),
+            )
+            .await;
+        }
+        if path.starts_with("/events") || path.starts_with("/overlay") {
+            return self.feed.handle(&mut s, path).await;
+        }
+        if self.cm.serve_hls
+            && let Some(name) = path.strip_prefix("/hls/")
+        {
+            return self.serve_hls_file(&mut s, name).await;
+        }
+        s.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: video/x-matroska\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+            .await?;
+        if method == "HEAD" {
+            return Ok(());
+        }
+        self.pipe_to(&mut s, &addr.to_string()).await
+    }
+
+    pub(super) async fn respond(s: &mut TcpStream, content_type: &str, body: &[u8]) -> Result<()> {
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+            content_type,
+            body.len()
+        );
+        s.write_all(head.as_bytes()).await?;
+        s.write_all(body).await?;
+        Ok(())
+    }
+
+    async fn serve_hls_file(&self, s: &mut TcpStream, name: &str) -> Result<()> {
+        let name = name.split('?').next().unwrap_or("");
+        if name.is_empty() {
+            return Self::respond(s, "text/html; charset=utf-8", HLS_PLAYER.as_bytes()).await;
+        }
+        let content_type = match name.rsplit_once('.').map(|it| it.1) {
+            Some("m3u8") => "application/vnd.apple.mpegurl",
+            Some("m4s") | Some("mp4") => "video/mp4",
+            Some("vtt") => "text/vtt",
+            _ => "application/octet-stream",
+        };
+        // only the flat files that ffmpeg writes into the directory
+        let data = match name.contains('/') || name.starts_with('.') {
+            true => None,
+            false => tokio::fs::read(format!("{}/{}", &self.hls_dir, name)).await.ok(),
+        };
+        match data {
+            Some(it) => Self::respond(s, content_type, &it).await,
+            None => {
+                s.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the stream from the latest cluster on until it ends, for http clients and local consumers alike.
+    pub async fn pipe_to<W: AsyncWrite + Unpin>(&self, w: &mut W, name: &str) -> Result<()> {
+        // nothing may be awaited between the snapshot and the subscription, or a cluster would be lost
+        let mut rx = self.tx.subscribe();
+        let snapshot = self.header.borrow().clone().map(|h| (h, self.cluster.borrow().clone()));
+        let client = Rc::new(Client {
+            since: Instant::now(),
+            sent: Cell::new(0),
+        });
+        self.clients.borrow_mut().insert(name.to_string(), client.clone());
+        info!("http client {} connected", name);
+        let ret = async {
+            if let Some((h, c)) = snapshot {
+                w.write_all(&h).await?;
+                client.sent.update(|it| it + h.len() as u64);
+                if let Some(c) = c {
+                    w.write_all(&c).await?;
+                    client.sent.update(|it| it + c.len() as u64);
+                }
+            }
+            loop {
+                let chunk = match rx.recv().await {
+                    Ok(it) => it,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        // chunks are whole clusters, so the client can pick up at the next one
+                        warn!("http client {} too slow, {} clusters dropped", name, n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if chunk.is_empty() {
+                    break;
+                }
+                w.write_all(&chunk).await?;
+                client.sent.update(|it| it + chunk.len() as u64);
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        self.clients.borrow_mut().remove(name);
+        info!("http client {} disconnected", name);
+        ret
+    }
+
+    /// Splits the output of ffmpeg into the header and whole clusters until ffmpeg is done.
+    pub async fn feed(&self, f2m: &str) -> Result<()> {
+        let mut s = TcpStream::connect(f2m.trim_start_matches("tcp://")).await?;
+        self.source_since.set(Some(Instant::now()));
+        let mut buf = BytesMut::new();
+        let mut header = BytesMut::new();
+        let ret = async {
+            while s.read_buf(&mut buf).await? > 0 {
+                self.split_elements(&mut buf, &mut header)?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        // the next ffmpeg starts over with a new header, the players have to reconnect for it
+        self.header.borrow_mut().take();
+        self.cluster.borrow_mut().take();
+        self.source_since.set(None);
+        let _ = self.tx.send(Bytes::new());
+        ret
+    }
+
+    fn split_elements(&self, buf: &mut BytesMut, header: &mut BytesMut) -> Result<()> {
+        loop {
+            if buf.first() == Some(&0) {
+                return Err(anyhow::anyhow!("invalid mkv element id"));
+            }
+            let (id, id_len, _) = match read_vint(buf, true) {
+                Some(it) => it,
+                None => return Ok(()),
+            };
+            let (size, size_len, unknown) = match read_vint(&buf[id_len..], false) {
+                Some(it) => it,
+                None => return Ok(()),
+            };
+            if id == SEGMENT_ID {
+                // only descend into the segment, its children are split one by one
+                header.extend_from_slice(&buf[..id_len + size_len]);
+                buf.advance(id_len + size_len);
+                continue;
+            }
+            if unknown {
+                return Err(anyhow::anyhow!("mkv element {:x} of unknown size", id));
+            }
+            let len = id_len + size_len + size as usize;
+            if buf.len() < len {
+                return Ok(());
+            }
+            let element = buf.split_to(len).freeze();
+            if self.header.borrow().is_none() {
+                if id != CLUSTER_ID {
+                    header.extend_from_slice(&element);
+                    continue;
+                }
+                let h = header.split().freeze();
+                *self.header.borrow_mut() = Some(h.clone());
+                let _ = self.tx.send(h);
+            }
+            if id == CLUSTER_ID {
+                *self.cluster.borrow_mut() = Some(element.clone());
+            } else if id == EBML_ID {
+                warn!("unexpected ebml header in the middle of the stream");
+            }
+            let _ = self.tx.send(element);
+        }
+    }
+
+    fn status_page(&self) -> String {
+        let mut rows = String::new();
+        let mut clients: Vec<_> = self.clients.borrow().iter().map(|(a, c)| (a.clone(), c.clone())).collect();
+        clients.sort_by_key(|it| it.1.since);
+        for (addr, c) in clients {
+            rows.push_str(&format!(
+                "<tr><td>{}</td><td>{}s</td><td>{:.1} MiB</td></tr>",
+                addr,
+                c.since.elapsed().as_secs(),
+                c.sent.get() as f64 / 1048576.0
+            ));
+        }
+        let state = match self.source_since.get() {
+            Some(it) if self.header.borrow().is_some() => {
+                let p = self.cm.ff_progress.borrow();
+                let mut state = format!(
+                    "live for {}s, {:.0} kbit/s, {:.1} fps, speed {:.2}x",
+                    it.elapsed().as_secs(),
+                    p.bitrate,
+                    p.fps,
+                    p.speed
+                );
+                if let Some(info) = self.cm.probe_info.borrow().as_ref() {
+                    state.push_str(&format!(
+                        ", {} {}x{}, {}",
+                        info.video_codec, info.width, info.height, info.audio_codec
+                    ));
+                }
+                state
+            }
+            Some(_) => "starting".to_string(),
+            None => "waiting for the stream".to_string(),
+        };
+        format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>dmlive</title></head><body>\
+            <h3>{}</h3><p><a href=\"{}\">{}</a></p><p>{} &middot; <a href=\"/\">stream</a>{}</p>\
+            <table><tr><th>client</th><th>connected</th><th>sent</th></tr>{}</table></body></html>",
+            html_escape::encode_text(&*self.cm.title.borrow()),
+            html_escape::encode_double_quoted_attribute(&self.cm.room_url),
+            html_escape::encode_text(&self.cm.room_url),
+            state,
+            if self.cm.serve_hls {
+                " &middot; <a href=\"/hls/\">hls</a>"
+            } else {
+                ""
+            },
+            rows
+        )
+    }
+}
@@ -1,5 +1,10 @@
+pub mod control;
+pub mod feed;
+pub mod http;
+
 use crate::config::{ConfigManager, Platform};
 use crate::dmlerr;
+use crate::dmlive::DMLMessage;
 use anyhow::Result;
 use std::rc::Rc;
 use tokio::{
@@ -15,6 +20,7 @@ pub struct IPCManager {
     base_uuid: String,
     base_socket_dir: String,
     f2m_port: u16,
+    f2m_in_port: u16,
     danmaku_port: u16,
     video_port: u16,
     audio_port: u16,
@@ -22,17 +28,26 @@ pub struct IPCManager {
     danmaku_tcp_listener: Option<TcpListener>,
     video_tcp_listener: Option<TcpListener>,
     audio_tcp_listener: Option<TcpListener>,
+    f2m_tcp_listener: Option<TcpListener>,
+    f2m_in_tcp_listener: Option<TcpListener>,
+    http_hub: http::HttpHub,
+    control: Rc<control::Control>,
+    feed: Rc<feed::DanmakuFeed>,
     // cm: Rc<ConfigManager>,
     cm: Rc<ConfigManager>,
 }
 
 impl IPCManager {
-    pub fn new(cm: Rc<ConfigManager>) -> Self {
+    pub fn new(cm: Rc<ConfigManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
         let base_uuid = Uuid::new_v4().as_hyphenated().to_string();
+        let hls_dir = format!("/tmp/dml-{}-hls", &base_uuid);
+        let control = Rc::new(control::Control::new(cm.clone(), mtx));
+        let feed = Rc::new(feed::DanmakuFeed::new(cm.clone()));
         IPCManager {
             base_uuid,
             base_socket_dir: "/tmp".into(),
             f2m_port: 0,
+            f2m_in_port: 0,
             danmaku_port: 0,
             video_port: 0,
             audio_port: 0,
@@ -40,6 +55,11 @@ impl IPCManager {
             danmaku_tcp_listener: None,
             video_tcp_listener: None,
             audio_tcp_listener: None,
+            f2m_tcp_listener: None,
+            f2m_in_tcp_listener: None,
+            http_hub: http::HttpHub::new(cm.clone(), hls_dir, control.clone(), feed.clone()),
+            control,
+            feed,
             cm,
         }
     }
@@ -58,6 +78,10 @@ impl IPCManager {
             ))
             .await;
         }
+        let _ = tokio::fs::remove_dir_all(self.get_hls_dir()).await;
+        if let Some(it) = self.cm.control.as_ref() {
+            let _ = tokio::fs::remove_file(it).await;
+        }
         Ok(())
     }
 
@@ -87,8 +111,12 @@ impl IPCManager {
     }
 
     async fn init_f2m(&mut self) -> Result<()> {
-        let (_, p) = Self::get_tcp_listener().await;
+        let (l, p) = Self::get_tcp_listener().await;
         self.f2m_port = p;
+        self.f2m_tcp_listener = Some(l);
+        let (l, p) = Self::get_tcp_listener().await;
+        self.f2m_in_port = p;
+        self.f2m_in_tcp_listener = Some(l);
         Ok(())
     }
 
@@ -109,10 +137,19 @@ impl IPCManager {
         format!("{}/dml-{}-mpv", &self.base_socket_dir, &self.base_uuid)
     }
 
+    pub fn get_hls_dir(&self) -> String {
+        format!("{}/dml-{}-hls", &self.base_socket_dir, &self.base_uuid)
+    }
+
     pub fn get_f2m_socket_path(&self) -> String {
         format!("tcp://127.0.0.1:{}", &self.f2m_port)
     }
 
+    /// Where ffmpeg sends its output, it connects once all of its inputs are open.
+    pub fn get_f2m_in_socket_path(&self) -> String {
+        format!("tcp://127.0.0.1:{}", &self.f2m_in_port)
+    }
+
     pub fn get_video_socket_path(&self) -> String {
         format!("tcp://127.0.0.1:{}", &self.video_port)
     }
@@ -132,6 +169,18 @@ impl IPCManager {
         // }
     }
 
+    pub fn get_http_hub(&self) -> &http::HttpHub {
+        &self.http_hub
+    }
+
+    pub fn get_control(&self) -> &control::Control {
+        &self.control
+    }
+
+    pub fn get_danmaku_feed(&self) -> &feed::DanmakuFeed {
+        &self.feed
+    }
+
     pub async fn get_danmaku_socket(&self) -> Result<Box<dyn DMLStream>> {
         // if self.cm.plat == Platform::Linux {
         //     let (s, _) = self.danmaku_unix_listener.as_ref().ok_or_else(|| dmlerr!())?.accept().await?;
@@ -142,6 +191,17 @@ impl IPCManager {
         // }
     }
 
+    pub async fn get_f2m_in_socket(&self) -> Result<Box<dyn DMLStream>> {
+        let (s, _) = self.f2m_in_tcp_listener.as_ref().ok_or_else(|| dmlerr!())?.accept().await?;
+        Ok(Box::new(s))
+    }
+
+    /// The player, the recorder or the http hub, whichever reads the output of ffmpeg.
+    pub async fn get_f2m_socket(&self) -> Result<Box<dyn DMLStream>> {
+        let (s, _) = self.f2m_tcp_listener.as_ref().ok_or_else(|| dmlerr!())?.accept().await?;
+        Ok(Box::new(s))
+    }
+
     pub async fn get_video_socket(&self) -> Result<Box<dyn DMLStream>> {
         let (s, _) = self.video_tcp_listener.as_ref().ok_or_else(|| dmlerr!())?.accept().await?;
         Ok(Box::new(s))
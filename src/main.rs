@@ -37,10 +37,10 @@ fn main() {
         let mut cm = ConfigManager::new(config_path, &args);
         cm.init().await.unwrap();
         let cm = Rc::new(cm);
-        let mut im = dmlive::ipcmanager::IPCManager::new(cm.clone());
+        let (mtx, mrx) = async_channel::unbounded();
+        let mut im = dmlive::ipcmanager::IPCManager::new(cm.clone(), mtx.clone());
         im.run().await.unwrap();
         let im = Rc::new(im);
-        let (mtx, mrx) = async_channel::unbounded();
         let ctx = dmlive::dmlive::DMLContext { im, cm, mrx, mtx };
         let dml = dmlive::dmlive::DMLive::new(Rc::new(ctx)).await;
         dml.run().await;
@@ -6,10 +6,13 @@ pub struct CmdParser {
     pub fsdown: bool,
     pub nick: bool,
     pub fps: bool,
+    pub live: bool,
     pub fs: Option<f64>,
     pub fa: Option<f64>,
     pub speed: Option<u64>,
     pub page: Option<u64>,
+    pub clip: Option<u64>,
+    pub mark: Option<String>,
 }
 
 impl CmdParser {
@@ -21,10 +24,13 @@ impl CmdParser {
         let mut fsdown = false;
         let mut nick = false;
         let mut fps = false;
+        let mut live = false;
         let mut fs = None;
         let mut fa = None;
         let mut speed = None;
         let mut page = None;
+        let mut clip = None;
+        let mut mark = None;
         if s.starts_with("dml:") {
             let s = &s[4..];
             let cmds: Vec<&str> = s.split(',').collect();
@@ -43,6 +49,8 @@ impl CmdParser {
                     nick = true;
                 } else if cmd.trim().eq("fps") {
                     fps = true;
+                } else if cmd.trim().eq("live") {
+                    live = true;
                 }
                 let subcmds: Vec<&str> = cmd.split('=').collect();
                 let mut iter = subcmds.iter();
@@ -71,6 +79,12 @@ impl CmdParser {
                         Ok(it) => Some(it),
                         Err(_) => None,
                     };
+                } else if arg1.trim().eq("clip") {
+                    let arg2 = *iter.next().unwrap_or(&"");
+                    clip = Some(arg2.parse::<u64>().unwrap_or(60));
+                } else if arg1.trim().eq("mark") {
+                    let arg2 = *iter.next().unwrap_or(&"");
+                    mark = Some(arg2.trim().to_string());
                 }
             }
         }
@@ -82,10 +96,13 @@ impl CmdParser {
             fsdown,
             nick,
             fps,
+            live,
             fs,
             fa,
             speed,
             page,
+            clip,
+            mark,
         }
     }
 }
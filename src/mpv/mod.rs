@@ -2,6 +2,7 @@ pub mod cmdparser;
 use crate::config::Platform;
 use crate::dmlerr;
 use crate::dmlive::DMLContext;
+use crate::streamer::health::HealthMonitor;
 use crate::{dmlive::DMLMessage, utils::gen_ua};
 use anyhow::Result;
 use futures::StreamExt;
@@ -17,8 +18,10 @@ use tokio::{
 
 pub struct MpvControl {
     last_rpc_ts: Cell<i64>,
+    clip_secs: Cell<u64>,
     mpv_command_tx: async_channel::Sender<String>,
     mpv_command_rx: async_channel::Receiver<String>,
+    health: HealthMonitor,
     ctx: Rc<DMLContext>,
 }
 impl MpvControl {
@@ -28,6 +31,8 @@ impl MpvControl {
             mpv_command_tx: tx,
             mpv_command_rx: rx,
             last_rpc_ts: Cell::new(0),
+            clip_secs: Cell::new(60),
+            health: HealthMonitor::new(ctx.clone()),
             ctx,
         }
     }
@@ -39,6 +44,15 @@ impl MpvControl {
                 .arg("--http-header-fields-add=Referer: https://www.bilibili.com/");
         } else {
             ret.args(&["--cache=yes", "--cache-pause-initial=yes"]);
+            if self.ctx.cm.timeshift > 0 && self.ctx.cm.site_type == crate::config::SiteType::Live {
+                // the danmaku track sits in the same cache, so it stays aligned with the rewound position;
+                // about 1 MiB/s covers most live streams. The forward cache takes what comes in during a pause,
+                // instead of holding up ffmpeg and the streamer
+                let size = self.ctx.cm.timeshift * 60;
+                ret.args(["--cache-on-disk=yes", "--demuxer-seekable-cache=yes", "--force-seekable=yes"])
+                    .arg(format!("--demuxer-max-bytes={}MiB", size))
+                    .arg(format!("--demuxer-max-back-bytes={}MiB", size));
+            }
         }
         ret.args(&[
             "--loop=no",
@@ -51,6 +65,13 @@ impl MpvControl {
             "--input-ipc-server={}",
             self.ctx.im.get_mpv_socket_path()
         ));
+        if self.ctx.cm.audio_only {
+            ret.args(["--no-video", "--force-window=no"]);
+        }
+        if self.ctx.cm.chat_ui {
+            // the terminal belongs to the chat view
+            ret.arg("--no-terminal");
+        }
         Ok(ret)
     }
 
@@ -78,6 +99,23 @@ impl MpvControl {
         Ok(())
     }
 
+    pub async fn reload_url_video(&self, url: &str) -> Result<()> {
+        info!("load video: {}--{}", url, self.ctx.cm.title.borrow());
+        self.mpv_command_tx
+            .send(format!(
+                "{{ \"command\": [\"loadfile\", \"{}\"], \"async\": true }}\n",
+                url
+            ))
+            .await?;
+        self.mpv_command_tx
+            .send(format!(
+                "{{ \"command\": [\"set_property\", \"force-media-title\", \"{}\"] }}\n",
+                self.ctx.cm.title.borrow().replace(r#"""#, r#"\""#)
+            ))
+            .await?;
+        Ok(())
+    }
+
     pub async fn reload_video(&self) -> Result<()> {
         if self.ctx.cm.plat == Platform::Android {
             Command::new("termux-open").arg(self.ctx.im.get_f2m_socket_path()).spawn()?;
@@ -112,10 +150,24 @@ impl MpvControl {
                 { "command": ["keybind", "alt+b", "script-message dml:back"] }
                 { "command": ["keybind", "alt+n", "script-message dml:next"] }
                 { "command": ["keybind", "alt+f", "script-message dml:fps"] }
+                { "command": ["keybind", "alt+l", "script-message dml:live"] }
+                { "command": ["keybind", "alt+c", "script-message dml:clip"] }
+                { "command": ["keybind", "alt+m", "script-message dml:mark"] }
                 "#
                 .into(),
             )
             .await?;
+        // a new player starts playing, a paused one reports it right away
+        self.ctx.cm.paused.set(false);
+        if self.ctx.cm.timeshift > 0 {
+            self.mpv_command_tx
+                .send(
+                    r#"{ "command": ["observe_property", 1, "pause"] }
+                    "#
+                    .into(),
+                )
+                .await?;
+        }
 
         Ok(())
     }
@@ -126,7 +178,11 @@ impl MpvControl {
             if rid.as_u64().eq(&Some(114)) {
                 let w = j.pointer("/data/w").ok_or_else(|| dmlerr!())?.as_u64().unwrap();
                 let h = j.pointer("/data/h").ok_or_else(|| dmlerr!())?.as_u64().unwrap();
-                if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo) {
+                self.health.reset();
+                self.health.check_resolution(w, h).await;
+                if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo | crate::config::Site::TwitchVideo)
+                    || (self.ctx.cm.site == crate::config::Site::Local && self.ctx.cm.danmaku_file.is_some())
+                {
                     let _ = self.ctx.mtx.send(DMLMessage::SetVideoInfo((w, h, 0))).await;
                     self.mpv_command_tx
                         .send(
@@ -142,6 +198,42 @@ impl MpvControl {
                         ))
                         .await?;
                 }
+            } else if rid.as_u64().eq(&Some(810)) {
+                if let (Some(w), Some(h)) = (
+                    j.pointer("/data/w").and_then(|x| x.as_u64()),
+                    j.pointer("/data/h").and_then(|x| x.as_u64()),
+                ) {
+                    self.health.check_resolution(w, h).await;
+                }
+            } else if rid.as_u64().eq(&Some(1146)) {
+                if let Some(it) = j.pointer("/data").and_then(|x| x.as_f64()) {
+                    self.mpv_command_tx
+                        .send(format!(
+                            "{{ \"command\": [\"seek\", \"{:.3}\", \"absolute\"] }}\n{{ \"command\": [\"set_property\", \"pause\", false] }}\n",
+                            (it - 1.0).max(0.0)
+                        ))
+                        .await?;
+                }
+            } else if rid.as_u64().eq(&Some(1147)) {
+                if let Some(it) = j.pointer("/data").and_then(|x| x.as_f64()) {
+                    // the cache holds every track, the clip keeps the danmaku subtitles
+                    let filename = self.ctx.cm.output_file_name("clip.mkv");
+                    info!("export clip: {}", &filename);
+                    self.mpv_command_tx
+                        .send(format!(
+                            "{}\n",
+                            serde_json::json!({
+                                "command": [
+                                    "dump-cache",
+                                    (it - self.clip_secs.get() as f64).max(0.0),
+                                    it,
+                                    filename
+                                ],
+                                "async": true
+                            })
+                        ))
+                        .await?;
+                }
             } else if rid.as_u64().eq(&Some(514)) {
                 match j.pointer("/data") {
                     Some(it) => match it.as_f64() {
@@ -183,6 +275,10 @@ impl MpvControl {
                 // tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
                 // let _ = self.reload_video().await;
             }
+        } else if event.eq("property-change") {
+            if j.pointer("/name").and_then(|x| x.as_str()) == Some("pause") {
+                self.ctx.cm.paused.set(j.pointer("/data").and_then(|x| x.as_bool()).unwrap_or(false));
+            }
         } else if event.eq("file-loaded") {
             tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
             let _ = self
@@ -205,7 +301,7 @@ impl MpvControl {
                 j.pointer("/args/0").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
             );
             if cmds.restart {
-                if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo) {
+                if matches!(self.ctx.cm.site, crate::config::Site::BiliVideo | crate::config::Site::TwitchVideo) {
                     let _ = self.ctx.mtx.send(DMLMessage::PlayVideo).await;
                 } else {
                     self.stop().await?;
@@ -240,6 +336,29 @@ impl MpvControl {
                 self.ctx.cm.bvideo_info.borrow_mut().current_page += 1;
                 let _ = self.ctx.mtx.send(DMLMessage::PlayVideo).await;
             }
+            if cmds.live {
+                // back to the live edge, i.e. the end of the timeshift buffer
+                self.mpv_command_tx
+                    .send(
+                        r#"{ "command": ["get_property", "demuxer-cache-time"], "request_id": 1146, "async": true }
+                        "#
+                        .into(),
+                    )
+                    .await?;
+            }
+            if let Some(it) = cmds.clip {
+                self.clip_secs.set(it);
+                self.mpv_command_tx
+                    .send(
+                        r#"{ "command": ["get_property", "time-pos"], "request_id": 1147, "async": true }
+                        "#
+                        .into(),
+                    )
+                    .await?;
+            }
+            if let Some(it) = cmds.mark {
+                let _ = self.ctx.mtx.send(DMLMessage::AddMark(it)).await;
+            }
             if cmds.fps {
                 let fps: u64 = {
                     let df = self.ctx.cm.display_fps.get();
@@ -295,10 +414,28 @@ impl MpvControl {
                 }
             }
         };
+        // live streams may fall back to a lower resolution without a reload, e.g. to a "host is away" picture
+        let video_params_task = async {
+            if self.ctx.cm.site_type != crate::config::SiteType::Live {
+                futures::future::pending::<()>().await;
+            }
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+                let _ = self
+                    .mpv_command_tx
+                    .send(
+                        r#"{ "command": ["get_property", "video-params"], "request_id": 810, "async": true }
+                        "#
+                        .into(),
+                    )
+                    .await;
+            }
+        };
         let _ = self.init_mpv_rpc().await;
         // let _ = self.reload_video().await;
         tokio::select! {
             _ = mpv_rpc_write_task => {},
+            _ = video_params_task => {},
             _ = mpv_rpc_read_task => {},
             _ = mpv.wait() => {},
         }
@@ -0,0 +1,544 @@
+use super::{
+    health::{self, HealthMonitor},
+    segment::MediaSegment,
+};
+use crate::{
+    dmlive::{DMLContext, DMLMessage},
+    ipcmanager::DMLStream,
+};
+use log::info;
+use reqwest::Client;
+use roxmltree::Node;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    ops::Not,
+    rc::Rc,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+/// Parses an xs:duration like `PT1H2M3.5S`, months and years are not supported.
+fn parse_duration(v: &str) -> Option<f64> {
+    let mut ret = 0.0;
+    let mut num = String::new();
+    let mut in_time = false;
+    for c in v.trim().strip_prefix('P')?.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' => num.push(c),
+            _ => {
+                let unit = match (in_time, c) {
+                    (false, 'D') => 86400.0,
+                    (true, 'H') => 3600.0,
+                    (true, 'M') => 60.0,
+                    (true, 'S') => 1.0,
+                    _ => return None,
+                };
+                ret += num.parse::<f64>().ok()? * unit;
+                num.clear();
+            }
+        }
+    }
+    Some(ret)
+}
+
+/// Expands `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$`, with an optional `%0Nd` width.
+fn expand_template(t: &str, rep_id: &str, bandwidth: u64, number: u64, time: u64) -> String {
+    let mut ret = String::new();
+    let mut parts = t.split('$');
+    if let Some(it) = parts.next() {
+        ret.push_str(it);
+    }
+    let mut in_id = true;
+    for p in parts {
+        if in_id.not() {
+            ret.push_str(p);
+            in_id = true;
+            continue;
+        }
+        in_id = false;
+        let (id, fmt) = p.split_once('%').unwrap_or((p, ""));
+        let width = fmt.trim_start_matches('0').trim_end_matches('d').parse::<usize>().unwrap_or(0);
+        match id {
+            "" => ret.push('$'),
+            "RepresentationID" => ret.push_str(rep_id),
+            "Bandwidth" => ret.push_str(&format!("{:0width$}", bandwidth)),
+            "Number" => ret.push_str(&format!("{:0width$}", number)),
+            "Time" => ret.push_str(&format!("{:0width$}", time)),
+            _ => {
+                ret.push('$');
+                ret.push_str(p);
+                ret.push('$');
+            }
+        }
+    }
+    ret
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|it| it.tag_name().name() == name)
+}
+
+#[derive(Debug, Default)]
+pub struct MPD {
+    dynamic: bool,
+    update_period: f64, // in seconds
+    video: Option<Vec<MediaSegment>>,
+    audio: Option<Vec<MediaSegment>>,
+}
+
+impl MPD {
+    /// Picks the highest bandwidth representation of each content type and lists its segments,
+    /// starting with the initialization segment if there is one.
+    /// Static presentations use the first period, dynamic ones the last.
+    pub fn decode(text: &str, mpd_url: &str) -> anyhow::Result<Self> {
+        let doc = roxmltree::Document::parse(text)?;
+        let root = doc.root_element();
+        let dynamic = root.attribute("type") == Some("dynamic");
+        let mut ret = MPD {
+            dynamic,
+            update_period: root.attribute("minimumUpdatePeriod").and_then(parse_duration).unwrap_or(2.0),
+            ..Default::default()
+        };
+        let periods: Vec<_> = root.children().filter(|it| it.tag_name().name() == "Period").collect();
+        let period = match if dynamic { periods.last() } else { periods.first() } {
+            Some(it) => *it,
+            None => return Err(anyhow::anyhow!("no period in mpd")),
+        };
+        let ast = root
+            .attribute("availabilityStartTime")
+            .and_then(|it| chrono::DateTime::parse_from_rfc3339(it).ok())
+            .map(|it| it.timestamp_millis() as f64 / 1000.0);
+        let period_start = period.attribute("start").and_then(parse_duration).unwrap_or(0.0);
+        let ctx = PeriodContext {
+            dynamic,
+            // seconds since the period started
+            now: ast.map(|it| chrono::Utc::now().timestamp_millis() as f64 / 1000.0 - it - period_start),
+            duration: period
+                .attribute("duration")
+                .or_else(|| root.attribute("mediaPresentationDuration"))
+                .and_then(parse_duration),
+        };
+        let mut base = url::Url::parse(mpd_url)?;
+        for n in [root, period] {
+            if let Some(b) = child(n, "BaseURL").and_then(|it| it.text()) {
+                base = base.join(b.trim())?;
+            }
+        }
+        for aset in period.children().filter(|it| it.tag_name().name() == "AdaptationSet") {
+            let content_type = |n: Node| {
+                let mime = n.attribute("contentType").or_else(|| n.attribute("mimeType")).unwrap_or("");
+                if mime.starts_with("video") {
+                    Some(false)
+                } else if mime.starts_with("audio") {
+                    Some(true)
+                } else {
+                    None
+                }
+            };
+            let reps: Vec<_> = aset.children().filter(|it| it.tag_name().name() == "Representation").collect();
+            let rep = match reps.iter().max_by_key(|it| it.attribute("bandwidth").and_then(|b| b.parse::<u64>().ok())) {
+                Some(it) => *it,
+                None => continue,
+            };
+            let is_audio = match content_type(aset).or_else(|| content_type(rep)) {
+                Some(it) => it,
+                None => continue,
+            };
+            let track = if is_audio { &mut ret.audio } else { &mut ret.video };
+            if track.is_some() {
+                continue;
+            }
+            let mut base = base.clone();
+            for n in [aset, rep] {
+                if let Some(b) = child(n, "BaseURL").and_then(|it| it.text()) {
+                    base = base.join(b.trim())?;
+                }
+            }
+            *track = Some(ctx.segments(&base, aset, rep)?);
+        }
+        if ret.video.is_none() && ret.audio.is_none() {
+            return Err(anyhow::anyhow!("no playable representation in mpd"));
+        }
+        Ok(ret)
+    }
+}
+
+struct PeriodContext {
+    dynamic: bool,
+    now: Option<f64>,
+    duration: Option<f64>,
+}
+
+impl PeriodContext {
+    fn segments(&self, base: &url::Url, aset: Node, rep: Node) -> anyhow::Result<Vec<MediaSegment>> {
+        let rep_id = rep.attribute("id").unwrap_or("");
+        let bandwidth = rep.attribute("bandwidth").and_then(|it| it.parse().ok()).unwrap_or(0);
+        let mut ret = Vec::new();
+        // the representation's own element overrides the adaptation set's
+        let templates: Vec<_> = [rep, aset].into_iter().filter_map(|it| child(it, "SegmentTemplate")).collect();
+        let attr = |name: &str| templates.iter().find_map(|it| it.attribute(name));
+        if templates.is_empty().not() {
+            let timescale = attr("timescale").and_then(|it| it.parse::<u64>().ok()).unwrap_or(1).max(1);
+            let start_number = attr("startNumber").and_then(|it| it.parse::<u64>().ok()).unwrap_or(1);
+            let pto = attr("presentationTimeOffset").and_then(|it| it.parse::<u64>().ok()).unwrap_or(0);
+            let media = attr("media").unwrap_or("");
+            let by_time = media.contains("$Time");
+            if let Some(init) = attr("initialization") {
+                ret.push(MediaSegment {
+                    is_header: true,
+                    url: base.join(&expand_template(init, rep_id, bandwidth, 0, 0))?.to_string(),
+                    ..Default::default()
+                });
+            }
+            // (number, time, duration) in timescale units
+            let mut list: Vec<(u64, u64, u64)> = Vec::new();
+            if let Some(tl) = templates.iter().find_map(|it| child(*it, "SegmentTimeline")) {
+                let ss: Vec<_> = tl.children().filter(|it| it.tag_name().name() == "S").collect();
+                let mut t = 0u64;
+                let mut number = start_number;
+                for (i, s) in ss.iter().enumerate() {
+                    t = s.attribute("t").and_then(|it| it.parse().ok()).unwrap_or(t);
+                    let d: u64 = s.attribute("d").and_then(|it| it.parse().ok()).unwrap_or(0).max(1);
+                    let r: i64 = s.attribute("r").and_then(|it| it.parse().ok()).unwrap_or(0);
+                    let end = if r >= 0 {
+                        t + d * (r as u64 + 1)
+                    } else {
+                        // repeats until the next element, the live edge or the end of the period
+                        let next = ss.get(i + 1).and_then(|it| it.attribute("t")).and_then(|it| it.parse().ok());
+                        let limit = if self.dynamic { self.now } else { self.duration };
+                        next.or_else(|| limit.map(|it| pto + (it * timescale as f64) as u64)).unwrap_or(t + d)
+                    };
+                    while t + d <= end {
+                        list.push((number, t, d));
+                        number += 1;
+                        t += d;
+                    }
+                }
+            } else if let Some(d) = attr("duration").and_then(|it| it.parse::<u64>().ok()) {
+                let dur = d as f64 / timescale as f64;
+                let range = if self.dynamic {
+                    // only the segments that are already complete
+                    let last = (self.now.unwrap_or(0.0) / dur).floor() as u64;
+                    last.saturating_sub(3)..last
+                } else {
+                    0..self.duration.map_or(1, |it| (it / dur).ceil() as u64)
+                };
+                list.extend(range.map(|i| (start_number + i, pto + i * d, d)));
+            }
+            for (number, time, d) in list {
+                ret.push(MediaSegment {
+                    sequence: if by_time { time } else { number },
+                    duration: d as f64 / timescale as f64,
+                    url: base.join(&expand_template(media, rep_id, bandwidth, number, time))?.to_string(),
+                    ..Default::default()
+                });
+            }
+        } else if let Some(sl) = [rep, aset].into_iter().find_map(|it| child(it, "SegmentList")) {
+            if let Some(init) = child(sl, "Initialization") {
+                ret.push(MediaSegment {
+                    is_header: true,
+                    byte_range: init.attribute("range").and_then(parse_range),
+                    url: base.join(init.attribute("sourceURL").unwrap_or(""))?.to_string(),
+                    ..Default::default()
+                });
+            }
+            let start_number = sl.attribute("startNumber").and_then(|it| it.parse::<u64>().ok()).unwrap_or(1);
+            for (i, s) in sl.children().filter(|it| it.tag_name().name() == "SegmentURL").enumerate() {
+                ret.push(MediaSegment {
+                    sequence: start_number + i as u64,
+                    byte_range: s.attribute("mediaRange").and_then(parse_range),
+                    url: base.join(s.attribute("media").unwrap_or(""))?.to_string(),
+                    ..Default::default()
+                });
+            }
+        } else {
+            // SegmentBase or a bare BaseURL, the whole resource with its index and initialization
+            ret.push(MediaSegment {
+                url: base.to_string(),
+                ..Default::default()
+            });
+        }
+        Ok(ret)
+    }
+}
+
+/// Parses a `first-last` byte range into (offset, length).
+fn parse_range(v: &str) -> Option<(u64, u64)> {
+    let (a, b) = v.split_once('-')?;
+    let (a, b): (u64, u64) = (a.trim().parse().ok()?, b.trim().parse().ok()?);
+    Some((a, b.checked_sub(a)? + 1))
+}
+
+pub struct DASH {
+    url: String,
+    dynamic: Cell<bool>,
+    watch_dog: Cell<bool>,
+    stream_ready: Cell<bool>,
+    last_sequence: [Cell<Option<u64>>; 2], // video, audio
+    health: HealthMonitor,
+    ctx: Rc<DMLContext>,
+}
+
+impl DASH {
+    pub fn new(stream_info: &HashMap<&str, String>, ctx: Rc<DMLContext>) -> Self {
+        DASH {
+            url: stream_info["url"].to_string(),
+            dynamic: Cell::new(false),
+            watch_dog: Cell::new(false),
+            stream_ready: Cell::new(false),
+            last_sequence: [Cell::new(None), Cell::new(None)],
+            health: HealthMonitor::new(ctx.clone()),
+            ctx,
+        }
+    }
+
+    async fn fetch_mpd(client: &Client, url: &str) -> anyhow::Result<MPD> {
+        let text = client.get(url).send().await?.error_for_status()?.text().await?;
+        MPD::decode(&text, url)
+    }
+
+    /// Asks for the response headers only, the body of a live stream would not end.
+    pub async fn content_type(url: &str) -> anyhow::Result<String> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::utils::gen_ua())
+            .timeout(tokio::time::Duration::from_secs(10))
+            .build()?;
+        let resp = client.get(url).send().await?.error_for_status()?;
+        Ok(resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|it| it.to_str().ok())
+            .unwrap_or("")
+            .to_string())
+    }
+
+    /// Sets "url_a" when the manifest has an audio track, so that ffmpeg reads the audio socket.
+    pub async fn probe(stream_info: &mut HashMap<&str, String>) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::utils::gen_ua())
+            .timeout(tokio::time::Duration::from_secs(10))
+            .build()?;
+        let mpd = Self::fetch_mpd(&client, &stream_info["url"]).await?;
+        if mpd.audio.is_some() && mpd.video.is_some() {
+            stream_info.insert("url_a", stream_info["url"].clone());
+        } else {
+            stream_info.remove("url_a");
+        }
+        Ok(())
+    }
+
+    async fn refresh_task(
+        &self, client: &Client, tx_v: Sender<MediaSegment>, tx_a: Option<Sender<MediaSegment>>,
+    ) -> anyhow::Result<()> {
+        loop {
+            let mpd = Self::fetch_mpd(client, &self.url).await?;
+            self.dynamic.set(mpd.dynamic);
+            let mut tracks = vec![(false, mpd.video.or(mpd.audio.clone()), &tx_v)];
+            if let Some(tx) = tx_a.as_ref() {
+                tracks.push((true, mpd.audio, tx));
+            }
+            let mut itvl = mpd.update_period;
+            for (is_audio, segs, tx) in tracks {
+                let segs = segs.unwrap_or_default();
+                let last = self.last_sequence[is_audio as usize].get();
+                let mut new: Vec<_> = segs.into_iter().filter(|it| last.is_none_or(|l| it.sequence > l)).collect();
+                if last.is_none() && mpd.dynamic {
+                    // start near the live edge, keeping the initialization segment
+                    let media = new.iter().filter(|it| it.is_header.not()).count();
+                    let mut skip = media.saturating_sub(2);
+                    new.retain(|it| {
+                        if it.is_header || skip == 0 {
+                            return true;
+                        }
+                        skip -= 1;
+                        false
+                    });
+                } else {
+                    new.retain(|it| it.is_header.not());
+                }
+                if let Some(it) = new.iter().rev().find(|it| it.is_header.not()) {
+                    self.last_sequence[is_audio as usize].set(Some(it.sequence));
+                    itvl = itvl.min(it.duration.max(1.0));
+                }
+                for s in new {
+                    tx.send(s).await?;
+                }
+            }
+            if mpd.dynamic.not() {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs_f64(itvl.max(1.0))).await;
+        }
+        // dropping the senders lets the writers finish the queue and close the sockets
+        Ok(())
+    }
+
+    async fn write_task(
+        &self, client: &Client, mut stream: Box<dyn DMLStream>, mut rx: Receiver<MediaSegment>, is_audio: bool,
+    ) -> anyhow::Result<()> {
+        while let Some(seg) = rx.recv().await {
+            let mut req = client.get(&seg.url).header("Connection", "keep-alive");
+            if let Some((o, l)) = seg.byte_range {
+                req = req.header("Range", format!("bytes={}-{}", o, o + l.max(1) - 1));
+            }
+            let mut resp = req.send().await?.error_for_status()?;
+            let mut hasher = DefaultHasher::new();
+            let mut pts = None;
+            let mut first = true;
+            while let Some(chunk) = resp.chunk().await? {
+                if is_audio.not() && !self.stream_ready.get() {
+                    self.stream_ready.set(true);
+                    let _ = self.ctx.mtx.send(DMLMessage::StreamReady).await;
+                }
+                hasher.write(&chunk);
+                // the moof comes first, its tfdt is in the first chunk
+                if first {
+                    pts = health::first_pts(&chunk);
+                    first = false;
+                }
+                stream.write_all(&chunk).await?;
+                self.watch_dog.set(true);
+            }
+            if is_audio.not() && seg.is_header.not() && self.dynamic.get() {
+                self.health.check_segment(hasher.finish(), pts).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn watch_dog_task(&self) -> anyhow::Result<()> {
+        let mut cnt = 0;
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+            // a paused vod or timeshifted live stream blocks the writes for as long as it likes
+            if self.watch_dog.get() || self.dynamic.get().not() || self.ctx.cm.paused.get() {
+                cnt = 0;
+            } else {
+                cnt += 1;
+            }
+            self.ctx.cm.stream_idle.set(cnt);
+            if cnt > 15 {
+                info!("watch dog failed!");
+                return Err(anyhow::anyhow!("watch dog failed!"));
+            }
+            self.watch_dog.set(false);
+        }
+    }
+
+    pub async fn run(&self, has_audio: bool) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::utils::gen_ua())
+            .connect_timeout(tokio::time::Duration::from_secs(10))
+            .build()?;
+        let (tx_v, rx_v) = mpsc::channel(100);
+        let (tx_a, rx_a) = mpsc::channel(100);
+        let write_task = async {
+            let video = self.write_task(&client, self.ctx.im.get_video_socket().await?, rx_v, false);
+            if has_audio {
+                let audio = async { self.write_task(&client, self.ctx.im.get_audio_socket().await?, rx_a, true).await };
+                let (v, a) = tokio::join!(video, audio);
+                v?;
+                a?;
+            } else {
+                video.await?;
+            }
+            anyhow::Ok(())
+        };
+        let refresh_task = self.refresh_task(&client, tx_v, has_audio.then_some(tx_a));
+        tokio::select! {
+            it = async { tokio::try_join!(refresh_task, write_task) } => { it?; },
+            it = self.watch_dog_task() => { it?; },
+        }
+        info!("dash streamer exit");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("P1DT0.5S"), Some(86400.5));
+        assert_eq!(parse_duration("PT2S"), Some(2.0));
+        assert_eq!(parse_duration("P1Y"), None);
+        assert_eq!(parse_duration("1H"), None);
+    }
+
+    #[test]
+    fn template() {
+        assert_eq!(expand_template("seg-$Number%05d$.m4s", "v1", 0, 42, 0), "seg-00042.m4s");
+        assert_eq!(
+            expand_template("$RepresentationID$/$Bandwidth$/$Time$.m4s", "v1", 800000, 0, 9000),
+            "v1/800000/9000.m4s"
+        );
+        assert_eq!(expand_template("a$$b-$Unknown$.m4s", "", 0, 0, 0), "a$b-$Unknown$.m4s");
+    }
+
+    #[test]
+    fn timeline() {
+        let mpd = r#"<MPD type="static" mediaPresentationDuration="PT10S">
+            <Period>
+                <AdaptationSet contentType="video">
+                    <SegmentTemplate timescale="1000" initialization="init-$RepresentationID$.mp4" media="$RepresentationID$-$Time$.m4s">
+                        <SegmentTimeline><S t="0" d="2000" r="-1"/></SegmentTimeline>
+                    </SegmentTemplate>
+                    <Representation id="low" bandwidth="100"/>
+                    <Representation id="high" bandwidth="200"/>
+                </AdaptationSet>
+                <AdaptationSet mimeType="audio/mp4">
+                    <Representation id="a" bandwidth="64">
+                        <SegmentTemplate startNumber="3" duration="4" media="a-$Number%05d$.m4s"/>
+                    </Representation>
+                </AdaptationSet>
+            </Period>
+        </MPD>"#;
+        let mpd = MPD::decode(mpd, "https://example.com/live/manifest.mpd").unwrap();
+        assert!(mpd.dynamic.not());
+        let v = mpd.video.unwrap();
+        assert_eq!(v.len(), 6);
+        assert!(v[0].is_header);
+        assert_eq!(v[0].url, "https://example.com/live/init-high.mp4");
+        assert_eq!(v[5].url, "https://example.com/live/high-8000.m4s");
+        assert_eq!((v[5].sequence, v[5].duration), (8000, 2.0));
+        let a: Vec<_> = mpd.audio.unwrap().into_iter().map(|it| it.url).collect();
+        assert_eq!(
+            a,
+            [
+                "https://example.com/live/a-00003.m4s",
+                "https://example.com/live/a-00004.m4s",
+                "https://example.com/live/a-00005.m4s"
+            ]
+        );
+    }
+
+    #[test]
+    fn segment_list() {
+        let mpd = r#"<MPD>
+            <BaseURL>https://cdn.example.com/v/</BaseURL>
+            <Period>
+                <AdaptationSet mimeType="video/mp4">
+                    <Representation id="v" bandwidth="1">
+                        <SegmentList startNumber="5">
+                            <Initialization sourceURL="init.mp4" range="0-99"/>
+                            <SegmentURL media="s1.m4s"/>
+                            <SegmentURL media="s2.m4s" mediaRange="100-199"/>
+                        </SegmentList>
+                    </Representation>
+                </AdaptationSet>
+            </Period>
+        </MPD>"#;
+        let v = MPD::decode(mpd, "https://example.com/a.mpd").unwrap().video.unwrap();
+        assert_eq!(v.len(), 3);
+        assert_eq!((v[0].url.as_str(), v[0].byte_range), ("https://cdn.example.com/v/init.mp4", Some((0, 100))));
+        assert_eq!((v[1].sequence, v[1].byte_range), (5, None));
+        assert_eq!((v[2].sequence, v[2].byte_range), (6, Some((100, 100))));
+    }
+}
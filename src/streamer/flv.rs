@@ -1,14 +1,159 @@
+use super::health::HealthMonitor;
 use crate::{
     config::Site,
     dmlive::{DMLContext, DMLMessage},
+    ipcmanager::DMLStream,
+    streamfinder::StreamFinder,
 };
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::{info, warn};
-use std::{cell::Cell, collections::HashMap, rc::Rc};
+use std::{collections::HashMap, ops::Not, rc::Rc};
 use tokio::io::AsyncWriteExt;
 
+const TAG_AUDIO: u8 = 8;
+const TAG_VIDEO: u8 = 9;
+const TAG_SCRIPT: u8 = 18;
+
+pub struct FlvTag {
+    tag_type: u8,
+    timestamp: u32,
+    data: Bytes,
+}
+
+impl FlvTag {
+    fn is_keyframe(&self) -> bool {
+        self.tag_type == TAG_VIDEO && self.data.first().is_some_and(|it| it >> 4 == 1)
+    }
+
+    /// AVC/HEVC decoder configuration or AAC AudioSpecificConfig.
+    fn is_sequence_header(&self) -> bool {
+        match self.tag_type {
+            TAG_VIDEO => self.data.len() > 1 && self.data[1] == 0,
+            TAG_AUDIO => self.data.len() > 1 && self.data[0] >> 4 == 10 && self.data[1] == 0,
+            _ => false,
+        }
+    }
+
+    fn write_to(&self, out: &mut BytesMut, timestamp: u32) {
+        out.put_u8(self.tag_type);
+        out.put_uint(self.data.len() as u64, 3);
+        out.put_uint((timestamp & 0xffffff) as u64, 3);
+        out.put_u8((timestamp >> 24) as u8);
+        out.put_uint(0, 3);
+        out.put_slice(&self.data);
+        out.put_u32(self.data.len() as u32 + 11);
+    }
+}
+
+/// Splits an FLV byte stream into its header and tags.
+#[derive(Default)]
+pub struct FlvParser {
+    buf: BytesMut,
+    header: Option<Bytes>,
+}
+
+impl FlvParser {
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    pub fn next_tag(&mut self) -> anyhow::Result<Option<FlvTag>> {
+        if self.header.is_none() {
+            if self.buf.len() < 13 {
+                return Ok(None);
+            }
+            if &self.buf[0..3] != b"FLV" {
+                return Err(anyhow::anyhow!("invalid flv header"));
+            }
+            let offset = u32::from_be_bytes(self.buf[5..9].try_into()?) as usize;
+            if self.buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            // the header and PreviousTagSize0
+            self.header = Some(self.buf.split_to(offset + 4).freeze());
+        }
+        if self.buf.len() < 11 {
+            return Ok(None);
+        }
+        let size = ((self.buf[1] as usize) << 16) | ((self.buf[2] as usize) << 8) | self.buf[3] as usize;
+        if self.buf.len() < 11 + size + 4 {
+            return Ok(None);
+        }
+        let mut tag = self.buf.split_to(11 + size + 4);
+        let tag_type = tag.get_u8() & 0x1f;
+        tag.advance(3);
+        let ts = tag.get_uint(3) as u32 | ((tag.get_u8() as u32) << 24);
+        tag.advance(3);
+        Ok(Some(FlvTag {
+            tag_type,
+            timestamp: ts,
+            data: tag.split_to(size).freeze(),
+        }))
+    }
+}
+
+/// Joins the tags of successive connections into one continuous FLV stream.
+#[derive(Default)]
+struct FlvSplicer {
+    header_done: bool,
+    waiting_keyframe: bool,
+    shift: Option<i64>,
+    last_ts: i64,
+    seq_headers: HashMap<u8, Bytes>,
+}
+
+impl FlvSplicer {
+    fn reconnected(&mut self) {
+        self.waiting_keyframe = true;
+        self.shift = None;
+    }
+
+    fn splice(&mut self, parser: &FlvParser, tag: FlvTag, out: &mut BytesMut) {
+        if self.header_done.not()
+            && let Some(h) = parser.header.as_ref()
+        {
+            out.put_slice(h);
+            self.header_done = true;
+        }
+        if self.waiting_keyframe {
+            // the metadata would only repeat, the decoder needs a keyframe to pick up again
+            if tag.tag_type == TAG_SCRIPT || (tag.is_keyframe().not() && tag.is_sequence_header().not()) {
+                return;
+            }
+            if tag.is_keyframe() && tag.is_sequence_header().not() {
+                self.waiting_keyframe = false;
+            }
+        }
+        if tag.is_sequence_header() {
+            if self.seq_headers.get(&tag.tag_type) == Some(&tag.data) {
+                return;
+            }
+            self.seq_headers.insert(tag.tag_type, tag.data.clone());
+        }
+        // a new connection may restart from zero, its first keyframe continues where the last one stopped
+        let ts = match self.shift {
+            None if tag.is_sequence_header() => self.last_ts,
+            _ => {
+                let last_ts = self.last_ts;
+                let shift = *self.shift.get_or_insert_with(|| {
+                    if last_ts == 0 {
+                        0
+                    } else {
+                        last_ts + 40 - tag.timestamp as i64
+                    }
+                });
+                (tag.timestamp as i64 + shift).max(0)
+            }
+        };
+        self.last_ts = self.last_ts.max(ts);
+        tag.write_to(out, ts as u32);
+    }
+}
+
 #[allow(unused)]
 pub struct FLV {
     url: String,
+    health: HealthMonitor,
     ctx: Rc<DMLContext>,
 }
 
@@ -16,52 +161,86 @@ impl FLV {
     pub fn new(stream_info: &HashMap<&str, String>, ctx: Rc<DMLContext>) -> Self {
         FLV {
             url: stream_info["url"].to_string(),
+            health: HealthMonitor::new(ctx.clone()),
             ctx,
         }
     }
 
-    async fn download(&self) -> anyhow::Result<()> {
+    /// Streams one connection until it ends or stalls, returns whether any tag got through.
+    async fn download(
+        &self, client: &reqwest::Client, url: &str, stream: &mut Box<dyn DMLStream>, splicer: &mut FlvSplicer,
+    ) -> anyhow::Result<bool> {
+        let mut resp = client.get(url).header("Referer", self.ctx.cm.room_url.as_str());
+        if self.ctx.cm.plive && matches!(self.ctx.cm.site, Site::BiliLive) {
+            resp = resp.header("Cookie", self.ctx.cm.bcookie.as_str());
+        }
+        let mut resp = resp.send().await?.error_for_status()?;
+        let mut parser = FlvParser::default();
+        let mut out = BytesMut::new();
+        let mut got_tag = false;
+        loop {
+            let chunk = match tokio::time::timeout(tokio::time::Duration::from_secs(10), resp.chunk()).await {
+                Ok(it) => it?,
+                Err(_) => {
+                    warn!("connection too slow");
+                    break;
+                }
+            };
+            let chunk = match chunk {
+                Some(it) => it,
+                None => break,
+            };
+            parser.push(&chunk);
+            while let Some(tag) = parser.next_tag()? {
+                if tag.tag_type == TAG_VIDEO {
+                    self.health.check_pts(tag.timestamp as i64).await;
+                }
+                splicer.splice(&parser, tag, &mut out);
+                got_tag = true;
+            }
+            if out.is_empty().not() {
+                stream.write_all(&out).await?;
+                out.clear();
+            }
+        }
+        Ok(got_tag)
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
         let mut stream = self.ctx.im.get_video_socket().await?;
         let client = reqwest::Client::builder()
             .user_agent(crate::utils::gen_ua())
             .connect_timeout(tokio::time::Duration::from_secs(10))
             .build()?;
-        let url = self.url.clone();
-        let room_url = self.ctx.cm.room_url.clone();
-        let watch_dog = Cell::new(0);
-        let watchdog_task = async {
-            loop {
-                watch_dog.set(watch_dog.get() + 1);
-                tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-                if watch_dog.get() > 10 {
-                    warn!("connection too slow");
-                    break;
+        let mut url = self.url.clone();
+        let mut splicer = FlvSplicer::default();
+        let mut failures = 0;
+        let _ = self.ctx.mtx.send(DMLMessage::StreamReady).await;
+        loop {
+            match self.download(&client, &url, &mut stream, &mut splicer).await {
+                Ok(true) => failures = 0,
+                Ok(false) => failures += 1,
+                Err(e) => {
+                    info!("flv download error: {}", e);
+                    failures += 1;
                 }
             }
-        };
-        let dl_task = async {
-            let mut resp = client.get(url).header("Referer", room_url);
-            if self.ctx.cm.plive && matches!(self.ctx.cm.site, Site::BiliLive) {
-                resp = resp.header("Cookie", self.ctx.cm.bcookie.as_str());
+            if failures >= 4 {
+                break;
             }
-            let mut resp = resp.send().await?;
-            let _ = self.ctx.mtx.send(DMLMessage::StreamReady).await;
-            while let Some(chunk) = resp.chunk().await? {
-                stream.write_all(&chunk).await?;
-                watch_dog.set(0);
+            if failures >= 2 {
+                // the cdn node may be gone, ask the site for a fresh one
+                let sf = StreamFinder::new(self.ctx.clone());
+                match tokio::time::timeout(tokio::time::Duration::from_secs(20), sf.run()).await {
+                    Ok(Ok(it)) if it["url"].contains(".flv") => url = it["url"].to_string(),
+                    _ => break,
+                }
             }
-            info!("flv downloader exit normally");
-            anyhow::Ok(())
-        };
-        tokio::select! {
-            it = dl_task => { it?; }
-            _ = watchdog_task => {}
+            info!("flv reconnecting");
+            splicer.reconnected();
+            self.health.reset();
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
         }
-        Ok(())
-    }
-
-    pub async fn run(&self) -> anyhow::Result<()> {
-        self.download().await?;
         info!("flv streamer exit");
         Ok(())
     }
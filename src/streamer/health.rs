@@ -0,0 +1,158 @@
+use crate::{
+    config::Site,
+    dmlerr,
+    dmlive::{DMLContext, DMLMessage},
+};
+use log::info;
+use std::{
+    cell::Cell,
+    hash::{DefaultHasher, Hasher},
+    ops::Not,
+    rc::Rc,
+    time::Instant,
+};
+
+const REPEAT_LIMIT: u32 = 3;
+const STALL_SECS: u64 = 20;
+
+pub fn digest(data: &[u8]) -> u64 {
+    let mut h = DefaultHasher::new();
+    h.write(data);
+    h.finish()
+}
+
+/// Finds the first video timestamp of a segment, in ms for ts and in track timescale units for fmp4.
+pub fn first_pts(data: &[u8]) -> Option<i64> {
+    if data.len() >= 188 && data[0] == 0x47 {
+        for p in data.chunks_exact(188) {
+            let afc = (p[3] >> 4) & 3;
+            if p[1] & 0x40 == 0 || afc & 1 == 0 {
+                continue;
+            }
+            let offset = if afc & 2 != 0 { 5 + p[4] as usize } else { 4 };
+            let pes = match p.get(offset..) {
+                Some(it) if it.len() >= 14 => it,
+                _ => continue,
+            };
+            if pes[0..3] == [0, 0, 1] && (0xe0..=0xef).contains(&pes[3]) && pes[7] & 0x80 != 0 {
+                let b = &pes[9..14];
+                let pts = (((b[0] as i64 >> 1) & 7) << 30)
+                    | ((b[1] as i64) << 22)
+                    | ((b[2] as i64 >> 1) << 15)
+                    | ((b[3] as i64) << 7)
+                    | (b[4] as i64 >> 1);
+                return Some(pts / 90);
+            }
+        }
+        return None;
+    }
+    let i = data.windows(4).position(|it| it == b"tfdt")?;
+    let b = data.get(i + 4..)?;
+    if b.first()? & 1 == 1 {
+        Some(u64::from_be_bytes(b.get(4..12)?.try_into().ok()?) as i64)
+    } else {
+        Some(u32::from_be_bytes(b.get(4..8)?.try_into().ok()?) as i64)
+    }
+}
+
+/// Catches streams that keep delivering bytes without new pictures, which the watchdogs can not see.
+pub struct HealthMonitor {
+    last_digest: Cell<u64>,
+    repeats: Cell<u32>,
+    last_pts: Cell<Option<(i64, Instant)>>,
+    height: Cell<u64>,
+    reported: Cell<bool>,
+    ctx: Rc<DMLContext>,
+}
+
+impl HealthMonitor {
+    pub fn new(ctx: Rc<DMLContext>) -> Self {
+        Self {
+            last_digest: Cell::new(0),
+            repeats: Cell::new(0),
+            last_pts: Cell::new(None),
+            height: Cell::new(0),
+            reported: Cell::new(false),
+            ctx,
+        }
+    }
+
+    /// Forgets what was seen, e.g. after a discontinuity or when the player loads a new file.
+    pub fn reset(&self) {
+        self.last_digest.set(0);
+        self.repeats.set(0);
+        self.last_pts.set(None);
+        self.height.set(0);
+        self.reported.set(false);
+    }
+
+    async fn report(&self, reason: String) {
+        if self.reported.replace(true) {
+            return;
+        }
+        info!("stream unhealthy: {}", reason);
+        let _ = self.ctx.mtx.send(DMLMessage::StreamUnhealthy(reason)).await;
+    }
+
+    pub async fn check_segment(&self, digest: u64, pts: Option<i64>) {
+        if digest == self.last_digest.get() {
+            self.repeats.update(|it| it + 1);
+            if self.repeats.get() >= REPEAT_LIMIT {
+                self.report(format!(
+                    "the same segment was served {} times",
+                    self.repeats.get() + 1
+                ))
+                .await;
+            }
+        } else {
+            self.last_digest.set(digest);
+            self.repeats.set(0);
+        }
+        if let Some(pts) = pts {
+            self.check_pts(pts).await;
+        }
+    }
+
+    pub async fn check_pts(&self, pts: i64) {
+        match self.last_pts.get() {
+            Some((last, since)) if last == pts => {
+                if since.elapsed().as_secs() >= STALL_SECS {
+                    self.report(format!("timestamps stalled at {} for {}s", pts, STALL_SECS)).await;
+                }
+            }
+            _ => self.last_pts.set(Some((pts, Instant::now()))),
+        }
+    }
+
+    pub async fn check_resolution(&self, w: u64, h: u64) {
+        let height = self.height.get();
+        if h > height {
+            self.height.set(h);
+        } else if h * 3 < height * 2 && self.ctx.cm.downshifted.get().not() {
+            self.report(format!(
+                "resolution dropped from {}p to {}x{}",
+                height, w, h
+            ))
+            .await;
+        }
+    }
+
+    /// Polls the room status for sites that swap a replay loop in when the host is away.
+    pub async fn site_task(&self) -> anyhow::Result<()> {
+        if self.ctx.cm.site != Site::DouyuLive {
+            futures::future::pending::<()>().await;
+        }
+        let rid = url::Url::parse(&self.ctx.cm.room_url)?
+            .path_segments()
+            .and_then(|mut x| x.next_back())
+            .ok_or_else(|| dmlerr!())?
+            .to_string();
+        let client = reqwest::Client::builder().timeout(tokio::time::Duration::from_secs(10)).build()?;
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            if let Ok((_, _, _, false)) = crate::streamfinder::douyu::get_live_info(&client, &rid).await {
+                self.report("the room is offline or playing a replay loop".into()).await;
+            }
+        }
+    }
+}
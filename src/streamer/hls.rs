@@ -1,18 +1,231 @@
-use super::segment::MediaSegment;
+use super::health::{self, HealthMonitor};
+use super::segment::{KeyMethod, MediaSegment, SegmentKey};
 use crate::{
+    config::StreamType,
     dmlive::{DMLContext, DMLMessage},
+    ipcmanager::DMLStream,
     streamer::segment::SegmentStream,
 };
-use log::info;
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use futures::StreamExt;
+use log::{info, warn};
+use regex::Regex;
 use reqwest::Client;
 use std::{
     cell::{Cell, RefCell},
     collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hasher},
     ops::Not,
     rc::Rc,
 };
 use tokio::io::AsyncWriteExt;
 
+/// Splits an HLS attribute list (`KEY=VALUE,KEY="VALUE"`), keeping commas inside quoted strings.
+pub fn parse_attributes(v: &str) -> HashMap<String, String> {
+    let mut ret = HashMap::new();
+    let mut rest = v.trim();
+    while rest.is_empty().not() {
+        let (k, r) = match rest.split_once('=') {
+            Some(it) => it,
+            None => break,
+        };
+        let (val, r) = if let Some(r) = r.strip_prefix('"') {
+            let end = r.find('"').unwrap_or(r.len());
+            (&r[..end], r.get(end + 1..).unwrap_or(""))
+        } else {
+            r.split_once(',').map_or((r, ""), |(a, b)| (a, b))
+        };
+        ret.insert(k.trim().to_string(), val.to_string());
+        rest = r.trim_start_matches(',').trim();
+    }
+    ret
+}
+
+fn parse_key(v: &str) -> Option<SegmentKey> {
+    let attrs = parse_attributes(v);
+    let method = match attrs.get("METHOD").map(|it| it.as_str()) {
+        Some("AES-128") => KeyMethod::Aes128,
+        Some("SAMPLE-AES") | Some("SAMPLE-AES-CTR") => KeyMethod::SampleAes,
+        _ => return None,
+    };
+    let iv = attrs.get("IV").and_then(|it| {
+        let hex = it.trim_start_matches("0x").trim_start_matches("0X");
+        let n = u128::from_str_radix(hex, 16).ok()?;
+        Some(n.to_be_bytes())
+    });
+    Some(SegmentKey {
+        method,
+        uri: attrs.get("URI").cloned().unwrap_or_default(),
+        iv,
+    })
+}
+
+/// Parses `<length>[@<offset>]` of EXT-X-BYTERANGE.
+fn parse_byte_range(v: &str) -> Option<(Option<u64>, u64)> {
+    let (l, o) = match v.trim().split_once('@') {
+        Some((l, o)) => (l, Some(o.trim().parse().ok()?)),
+        None => (v, None),
+    };
+    Some((o, l.trim().parse().ok()?))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Variant {
+    pub bandwidth: u64,
+    pub resolution: Option<(u64, u64)>,
+    pub codecs: String,
+    pub frame_rate: f64,
+    pub name: String,          // VIDEO group id, e.g. "1080p60" or "audio_only" on twitch
+    pub audio: Option<String>, // AUDIO group id
+    pub url: String,
+}
+
+impl Variant {
+    fn from_attributes(v: &str, url: &str) -> Self {
+        let attrs = parse_attributes(v);
+        Variant {
+            bandwidth: attrs.get("BANDWIDTH").and_then(|it| it.parse().ok()).unwrap_or(1),
+            resolution: attrs.get("RESOLUTION").and_then(|it| {
+                let (w, h) = it.split_once('x')?;
+                Some((w.parse().ok()?, h.parse().ok()?))
+            }),
+            codecs: attrs.get("CODECS").cloned().unwrap_or_default(),
+            frame_rate: attrs.get("FRAME-RATE").and_then(|it| it.parse().ok()).unwrap_or(0.0),
+            name: attrs.get("VIDEO").cloned().unwrap_or_default(),
+            audio: attrs.get("AUDIO").cloned(),
+            url: url.to_string(),
+        }
+    }
+
+    pub fn is_audio_only(&self) -> bool {
+        self.name == "audio_only"
+            || (self.resolution.is_none()
+                && self.codecs.is_empty().not()
+                && self.codecs.split(',').all(|it| it.trim().starts_with("mp4a") || it.trim().starts_with("opus")))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rendition {
+    pub media_type: String,
+    pub group_id: String,
+    pub name: String,
+    pub language: String,
+    pub default: bool,
+    pub url: Option<String>, // None if the rendition is muxed into the variant
+}
+
+impl Rendition {
+    fn from_attributes(v: &str) -> Self {
+        let attrs = parse_attributes(v);
+        Rendition {
+            media_type: attrs.get("TYPE").cloned().unwrap_or_default(),
+            group_id: attrs.get("GROUP-ID").cloned().unwrap_or_default(),
+            name: attrs.get("NAME").cloned().unwrap_or_default(),
+            language: attrs.get("LANGUAGE").cloned().unwrap_or_default(),
+            default: attrs.get("DEFAULT").is_some_and(|it| it == "YES"),
+            url: attrs.get("URI").cloned(),
+        }
+    }
+}
+
+/// Variant selector given by `--hls-variant`, comma separated terms: `720`, `720p` (max height) or `720p60`
+/// (with max fps), `audio_only`, `codec=<prefix>`, `fps=<max>`, `lang=<audio language>`.
+#[derive(Debug, Clone, Default)]
+pub struct VariantSpec {
+    max_height: Option<u64>,
+    audio_only: bool,
+    codec: Option<String>,
+    max_fps: Option<f64>,
+    lang: Option<String>,
+}
+
+impl VariantSpec {
+    pub fn parse(spec: &str) -> Self {
+        let re = Regex::new(r"^(\d+)(?:p(\d+)?)?$").unwrap();
+        let mut ret = VariantSpec::default();
+        for term in spec.split(',').map(|it| it.trim()).filter(|it| it.is_empty().not()) {
+            match term.split_once('=') {
+                Some(("codec", v)) => ret.codec = Some(v.to_string()),
+                Some(("fps", v)) if v.parse::<f64>().is_ok() => ret.max_fps = v.parse().ok(),
+                Some(("lang", v)) => ret.lang = Some(v.to_string()),
+                _ if term == "audio_only" || term == "audio" => ret.audio_only = true,
+                _ => match re.captures(term) {
+                    Some(c) => {
+                        ret.max_height = c[1].parse().ok();
+                        if let Some(fps) = c.get(2) {
+                            ret.max_fps = fps.as_str().parse().ok();
+                        }
+                    }
+                    None => warn!("hls: unknown variant term {}", term),
+                },
+            }
+        }
+        ret
+    }
+
+    fn matches(&self, v: &Variant) -> bool {
+        if self.audio_only {
+            return v.is_audio_only();
+        }
+        if v.is_audio_only() {
+            return false;
+        }
+        if let Some(h) = self.max_height
+            && v.resolution.is_some_and(|it| it.1 > h)
+        {
+            return false;
+        }
+        if let Some(c) = self.codec.as_ref()
+            && v.codecs.split(',').any(|it| it.trim().starts_with(c.as_str())).not()
+        {
+            return false;
+        }
+        if let Some(fps) = self.max_fps
+            && v.frame_rate > fps + 0.5
+        {
+            return false;
+        }
+        true
+    }
+
+    /// Picks the highest bandwidth variant that matches, or the highest bandwidth one if nothing does.
+    pub fn select<'a>(&self, variants: &'a [Variant]) -> Option<&'a Variant> {
+        variants.iter().filter(|it| self.matches(it)).max_by_key(|it| it.bandwidth).or_else(|| {
+            info!("hls: no variant matches {:?}, using the best one", self);
+            variants.iter().max_by_key(|it| it.bandwidth)
+        })
+    }
+
+    /// Picks the audio rendition of the variant's group, by language or name, then DEFAULT=YES.
+    pub fn select_audio<'a>(&self, renditions: &'a [Rendition], variant: &Variant) -> Option<&'a Rendition> {
+        let group_id = variant.audio.as_ref()?;
+        let group: Vec<_> =
+            renditions.iter().filter(|it| it.media_type == "AUDIO" && it.group_id == *group_id).collect();
+        self.lang
+            .as_ref()
+            .and_then(|l| group.iter().find(|it| it.language.starts_with(l.as_str()) || it.name == *l))
+            .or_else(|| group.iter().find(|it| it.default))
+            .or(group.first())
+            .copied()
+    }
+}
+
+fn join_url(base: &str, clip: &str) -> anyhow::Result<String> {
+    let url = if clip.starts_with("http") {
+        clip.to_string()
+    } else {
+        let url = url::Url::parse(base)?;
+        let url2 = url.join(clip)?;
+        if url2.as_str().contains("?") {
+            url2.as_str().to_string()
+        } else {
+            format!("{}?{}", url2.as_str(), url.query().unwrap_or(""))
+        }
+    };
+    Ok(url)
+}
+
 #[allow(unused)]
 #[derive(Debug)]
 pub struct M3U8 {
@@ -20,102 +233,340 @@ pub struct M3U8 {
     target_duration: u64,
     props: HashMap<String, Vec<String>>,
     clips: VecDeque<MediaSegment>,
-    streams: VecDeque<(isize, String)>,
+    variants: Vec<Variant>,
+    renditions: Vec<Rendition>,
+    can_block_reload: bool,
+    part_target: f64,
+    parts: Vec<(u64, u64, MediaSegment)>, // (media sequence, part index, part)
+    preload_hint: Option<(u64, u64, MediaSegment)>,
 }
 
 // #[allow(unused)]
 pub struct HLS {
     url: RefCell<String>,
+    audio: bool,
+    spec: VariantSpec,
+    master: RefCell<Option<String>>,
+    bandwidth: Cell<u64>,  // of the current variant, 0 if unknown
+    slow_count: Cell<u32>, // consecutive segments fetched slower than the variant bandwidth
+    switched: Cell<bool>,
+    throughput: Cell<f64>,             // in bit/s
+    fallback: RefCell<Option<String>>, // taken once switched to
+    fallback_pending: Cell<bool>,
+    ad_break: Cell<Option<(std::time::Instant, f64)>>, // (start, total ad duration)
+    keys: RefCell<HashMap<String, [u8; 16]>>,
     header_done: Cell<bool>,
     watch_dog: Cell<bool>,
     stream_ready: Cell<bool>,
+    health: HealthMonitor,
     ctx: Rc<DMLContext>,
 }
 
 impl HLS {
     pub fn new(stream_info: &HashMap<&str, String>, ctx: Rc<DMLContext>) -> Self {
+        let mut ret = Self::new_track(&stream_info["url"], false, ctx);
+        ret.master = RefCell::new(stream_info.get("url_master").cloned());
+        ret.fallback = RefCell::new(stream_info.get("url_fallback").cloned());
+        ret.bandwidth.set(stream_info.get("bandwidth").and_then(|it| it.parse().ok()).unwrap_or(0));
+        ret
+    }
+
+    /// Streams the separate audio rendition ("url_a") to the audio socket.
+    pub fn new_audio(stream_info: &HashMap<&str, String>, ctx: Rc<DMLContext>) -> Self {
+        Self::new_track(&stream_info["url_a"], true, ctx)
+    }
+
+    fn new_track(url: &str, audio: bool, ctx: Rc<DMLContext>) -> Self {
         HLS {
-            url: RefCell::new(stream_info["url"].to_string()),
+            url: RefCell::new(url.to_string()),
+            audio,
+            spec: VariantSpec::parse(&ctx.cm.hls_variant),
+            master: RefCell::new(None),
+            bandwidth: Cell::new(0),
+            slow_count: Cell::new(0),
+            switched: Cell::new(false),
+            throughput: Cell::new(0.0),
+            fallback: RefCell::new(None),
+            fallback_pending: Cell::new(false),
+            ad_break: Cell::new(None),
+            keys: RefCell::new(HashMap::new()),
             watch_dog: Cell::new(false),
             header_done: Cell::new(false),
             stream_ready: Cell::new(false),
+            health: HealthMonitor::new(ctx.clone()),
             ctx,
         }
     }
 
-    pub fn decode_m3u8(m3u8_text: &str) -> anyhow::Result<M3U8> {
+    /// Resolves "url_master" into the media playlists picked by `--hls-variant`,
+    /// setting "url" and, when the audio rendition is a separate playlist, "url_a".
+    pub async fn resolve_master(stream_info: &mut HashMap<&str, String>, spec: &VariantSpec) -> anyhow::Result<()> {
+        let master = match stream_info.get("url_master") {
+            Some(it) => it.clone(),
+            None => return Ok(()),
+        };
+        let client = reqwest::Client::builder()
+            .user_agent(crate::utils::gen_ua())
+            .timeout(tokio::time::Duration::from_secs(10))
+            .build()?;
+        let m3u8_text = client.get(&master).send().await?.error_for_status()?.text().await?;
+        let m3u8 = Self::decode_m3u8(&m3u8_text, &master)?;
+        let selected = spec.select(&m3u8.variants).ok_or_else(|| anyhow::anyhow!("no hls variant found"))?;
+        // SAMPLE-AES can not be decrypted here, the other variants may be plain AES-128 or not encrypted at all
+        let mut candidates: Vec<_> = m3u8.variants.iter().filter(|it| it.url != selected.url).collect();
+        candidates.sort_by_key(|it| std::cmp::Reverse((spec.matches(it), it.bandwidth)));
+        candidates.insert(0, selected);
+        let mut variant = None;
+        for it in candidates {
+            match Self::is_sample_aes(&client, &it.url).await {
+                Ok(false) => {
+                    variant = Some(it);
+                    break;
+                }
+                Ok(true) => info!("hls variant {:?} is SAMPLE-AES encrypted, skipped", it),
+                Err(e) => info!("hls variant {:?} error: {}", it, e),
+            }
+        }
+        let variant = variant.ok_or_else(|| anyhow::anyhow!("every hls variant is SAMPLE-AES encrypted"))?;
+        info!("hls variant: {:?}", variant);
+        stream_info.insert("url", variant.url.clone());
+        stream_info.insert("bandwidth", variant.bandwidth.to_string());
+        stream_info.remove("url_a");
+        if let Some(it) = spec.select_audio(&m3u8.renditions, variant) {
+            info!("hls audio rendition: {:?}", it);
+            if let Some(u) = it.url.as_ref() {
+                stream_info.insert("url_a", u.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn is_sample_aes(client: &Client, url: &str) -> anyhow::Result<bool> {
+        let text = client.get(url).send().await?.error_for_status()?.text().await?;
+        Ok(Self::decode_m3u8(&text, url)?
+            .clips
+            .iter()
+            .any(|c| c.key.as_ref().is_some_and(|k| k.method == KeyMethod::SampleAes)))
+    }
+
+    /// Fails on a SAMPLE-AES media playlist, whose segments could only be fetched to fail decrypting.
+    pub async fn check_encryption(url: &str) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::utils::gen_ua())
+            .timeout(tokio::time::Duration::from_secs(10))
+            .build()?;
+        match Self::is_sample_aes(&client, url).await {
+            Ok(true) => Err(anyhow::anyhow!(
+                "the hls stream is SAMPLE-AES encrypted, which is not supported"
+            )),
+            Ok(false) => Ok(()),
+            // the streamer retries on its own
+            Err(e) => {
+                info!("hls playlist error: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Parses a master or a media playlist, every url in it is resolved against `base`, the url it came from.
+    pub fn decode_m3u8(m3u8_text: &str, base: &str) -> anyhow::Result<M3U8> {
         let mut lines = m3u8_text.lines();
         let mut sq = 0u64;
         let mut td = 5u64;
         let mut header = "".to_string();
         let mut m3u8_props = HashMap::new();
         let mut m3u8_clips = VecDeque::new();
-        let mut m3u8_streams = VecDeque::new();
+        let mut variants = Vec::new();
+        let mut renditions = Vec::new();
         let mut extinf = "".to_string();
         let mut ext_stream_inf = "".to_string();
+        let mut key = None;
+        let mut header_key = None;
+        let mut header_range = None;
+        let mut discontinuity = false;
+        let mut byte_range: Option<(Option<u64>, u64)> = None;
+        let mut last_range_end: Option<(String, u64)> = None;
+        let mut pdt: Option<i64> = None;
+        let mut can_block_reload = false;
+        let mut part_target = 0.0;
+        let mut parts = Vec::new();
+        let mut part_idx = 0u64;
+        let mut part_offset = 0.0; // seconds into the segment the next part starts at
+        let mut hint = None;
+        let mut ad_ranges = Vec::new();
         while let Some(line) = lines.next() {
             info!("{}", &line);
             let line = line.trim();
             if line.starts_with("#") {
-                if let Some((k, v)) = line.strip_prefix("#").unwrap().split_once(":") {
-                    let k = k.trim();
-                    let v = v.trim();
-                    if k.eq("EXT-X-MEDIA-SEQUENCE") {
-                        sq = v.parse().unwrap_or(0);
-                    } else if k.eq("EXT-X-TARGETDURATION") {
-                        td = v.parse().unwrap_or(5);
-                    } else if k.eq("EXT-X-MAP") {
-                        let (_, h) = v.split_once("=").unwrap_or(("", ""));
-                        let h = h.trim().strip_prefix('"').and_then(|it| it.strip_suffix('"')).unwrap_or("").trim();
-                        header.clear();
-                        header.push_str(h);
-                    } else if k.eq("EXTINF") {
-                        extinf.clear();
-                        extinf.push_str(v);
-                    } else if k.eq("EXT-X-STREAM-INF") {
-                        ext_stream_inf.clear();
-                        ext_stream_inf.push_str(v);
-                    } else {
-                        m3u8_props
-                            .entry(k.to_string())
-                            .and_modify(|it: &mut Vec<String>| it.push(v.to_string()))
-                            .or_insert(vec![v.to_string()]);
+                let tag = line.strip_prefix("#").unwrap();
+                let (k, v) = tag.split_once(":").unwrap_or((tag, ""));
+                let k = k.trim();
+                let v = v.trim();
+                if k.eq("EXT-X-MEDIA-SEQUENCE") {
+                    sq = v.parse().unwrap_or(0);
+                } else if k.eq("EXT-X-TARGETDURATION") {
+                    td = v.parse().unwrap_or(5);
+                } else if k.eq("EXT-X-MAP") {
+                    let attrs = parse_attributes(v);
+                    header.clear();
+                    header.push_str(attrs.get("URI").map(|it| it.trim()).unwrap_or(""));
+                    header_key = key.clone();
+                    header_range =
+                        attrs.get("BYTERANGE").and_then(|it| parse_byte_range(it)).map(|(o, l)| (o.unwrap_or(0), l));
+                } else if k.eq("EXT-X-KEY") {
+                    key = parse_key(v);
+                    if let Some(k) = key.as_mut()
+                        && k.method == KeyMethod::Aes128
+                    {
+                        k.uri = join_url(base, &k.uri)?;
                     }
+                } else if k.eq("EXTINF") {
+                    extinf.clear();
+                    extinf.push_str(v);
+                } else if k.eq("EXT-X-DISCONTINUITY") {
+                    discontinuity = true;
+                } else if k.eq("EXT-X-BYTERANGE") {
+                    byte_range = parse_byte_range(v);
+                } else if k.eq("EXT-X-PROGRAM-DATE-TIME") {
+                    pdt = chrono::DateTime::parse_from_rfc3339(v).ok().map(|it| it.timestamp_millis());
+                } else if k.eq("EXT-X-SERVER-CONTROL") {
+                    can_block_reload = parse_attributes(v).get("CAN-BLOCK-RELOAD").is_some_and(|it| it == "YES");
+                } else if k.eq("EXT-X-PART-INF") {
+                    part_target = parse_attributes(v).get("PART-TARGET").and_then(|it| it.parse().ok()).unwrap_or(0.0);
+                } else if k.eq("EXT-X-PART") {
+                    let attrs = parse_attributes(v);
+                    let uri = attrs.get("URI").cloned().unwrap_or_default();
+                    let byte_range = attrs.get("BYTERANGE").and_then(|it| parse_byte_range(it)).map(|(o, l)| {
+                        let o = o.unwrap_or_else(|| match last_range_end.as_ref() {
+                            Some((u, e)) if *u == uri => *e,
+                            _ => 0,
+                        });
+                        last_range_end = Some((uri.clone(), o + l));
+                        (o, l)
+                    });
+                    let msn = sq + m3u8_clips.len() as u64;
+                    let seg = MediaSegment {
+                        sequence: msn,
+                        key: key.clone(),
+                        duration: attrs.get("DURATION").and_then(|it| it.parse().ok()).unwrap_or(0.0),
+                        byte_range,
+                        program_date_time: pdt.map(|it| it + (part_offset * 1000.0) as i64),
+                        url: join_url(base, &uri)?,
+                        ..Default::default()
+                    };
+                    part_offset += seg.duration;
+                    parts.push((msn, part_idx, seg));
+                    part_idx += 1;
+                } else if k.eq("EXT-X-PRELOAD-HINT") {
+                    let attrs = parse_attributes(v);
+                    let start = attrs.get("BYTERANGE-START").and_then(|it| it.parse::<u64>().ok()).unwrap_or(0);
+                    let len = attrs.get("BYTERANGE-LENGTH").and_then(|it| it.parse::<u64>().ok());
+                    // open-ended sub-ranges are left to the next playlist reload
+                    if attrs.get("TYPE").is_some_and(|it| it == "PART") && (start == 0 || len.is_some()) {
+                        let msn = sq + m3u8_clips.len() as u64;
+                        let seg = MediaSegment {
+                            sequence: msn,
+                            key: key.clone(),
+                            byte_range: len.map(|l| (start, l)),
+                            program_date_time: pdt.map(|it| it + (part_offset * 1000.0) as i64),
+                            url: join_url(base, attrs.get("URI").map_or("", |it| it.as_str()))?,
+                            ..Default::default()
+                        };
+                        hint = Some((msn, part_idx, seg));
+                    }
+                } else if k.eq("EXT-X-DATERANGE") {
+                    let attrs = parse_attributes(v);
+                    let is_ad = attrs.get("CLASS").is_some_and(|it| it == "twitch-stitched-ad")
+                        || attrs.get("ID").is_some_and(|it| it.starts_with("stitched-ad"));
+                    let start = attrs
+                        .get("START-DATE")
+                        .and_then(|it| chrono::DateTime::parse_from_rfc3339(it).ok())
+                        .map(|it| it.timestamp_millis());
+                    let duration = attrs
+                        .get("DURATION")
+                        .or_else(|| attrs.get("PLANNED-DURATION"))
+                        .and_then(|it| it.parse::<f64>().ok());
+                    if let (true, Some(start), Some(duration)) = (is_ad, start, duration) {
+                        ad_ranges.push((start, start + (duration * 1000.0) as i64));
+                    }
+                } else if k.eq("EXT-X-MEDIA") {
+                    let mut r = Rendition::from_attributes(v);
+                    if let Some(u) = r.url.as_mut() {
+                        *u = join_url(base, u)?;
+                    }
+                    renditions.push(r);
+                } else if k.eq("EXT-X-STREAM-INF") {
+                    ext_stream_inf.clear();
+                    ext_stream_inf.push_str(v);
+                } else if v.is_empty().not() {
+                    m3u8_props
+                        .entry(k.to_string())
+                        .and_modify(|it: &mut Vec<String>| it.push(v.to_string()))
+                        .or_insert(vec![v.to_string()]);
                 }
             } else {
                 if line.is_empty().not() {
                     if ext_stream_inf.is_empty() {
+                        let duration =
+                            extinf.split(',').next().and_then(|it| it.trim().parse::<f64>().ok()).unwrap_or(0.0);
+                        // a sub-range without an offset continues right after the previous one of the same resource
+                        let byte_range = byte_range.take().map(|(o, l)| {
+                            let o = o.unwrap_or_else(|| match last_range_end.as_ref() {
+                                Some((u, e)) if u == line => *e,
+                                _ => 0,
+                            });
+                            last_range_end = Some((line.to_owned(), o + l));
+                            (o, l)
+                        });
                         let seg = MediaSegment {
-                            skip: if extinf.contains("Amazon") { 1 } else { 0 },
-                            props: HashMap::new(),
-                            url: line.to_owned(),
-                            is_header: false,
+                            ad: extinf.contains("Amazon") || extinf.contains("stitched-ad"),
+                            sequence: sq + m3u8_clips.len() as u64,
+                            key: key.clone(),
+                            duration,
+                            discontinuity,
+                            byte_range,
+                            program_date_time: pdt,
+                            url: join_url(base, line)?,
+                            ..Default::default()
                         };
                         m3u8_clips.push_back(seg);
+                        // later segments without their own tag continue from this one
+                        pdt = pdt.map(|it| it + (duration * 1000.0) as i64);
+                        discontinuity = false;
+                        part_idx = 0;
+                        part_offset = 0.0;
                         extinf.clear()
                     } else {
-                        let bw = ext_stream_inf
-                            .split(',')
-                            .find_map(|a| {
-                                if a.trim().starts_with("BANDWIDTH") {
-                                    return a.split("=").find_map(|x| x.trim().parse::<isize>().ok());
-                                }
-                                None
-                            })
-                            .unwrap_or(1);
-                        m3u8_streams.push_back((bw, line.to_owned()));
+                        variants.push(Variant::from_attributes(
+                            &ext_stream_inf,
+                            &join_url(base, line)?,
+                        ));
+                        ext_stream_inf.clear();
                         td = 1;
                         sq = 0;
                     }
                 }
             }
         }
+        let in_ad_range = |t: Option<i64>| t.is_some_and(|t| ad_ranges.iter().any(|(s, e)| (*s..*e).contains(&t)));
+        for c in m3u8_clips.iter_mut() {
+            if in_ad_range(c.program_date_time) {
+                c.ad = true;
+            }
+        }
+        // parts of a complete segment go with its title, the ones of the segment in progress only have the dates
+        for (msn, _, p) in parts.iter_mut().chain(hint.iter_mut()) {
+            p.ad = in_ad_range(p.program_date_time) || m3u8_clips.iter().any(|c| c.sequence == *msn && c.ad);
+        }
         if header.is_empty().not() {
             let seg = MediaSegment {
                 skip: 1,
-                props: HashMap::new(),
-                url: header,
+                sequence: sq,
+                key: header_key,
+                byte_range: header_range,
+                url: join_url(base, &header)?,
                 is_header: true,
+                ..Default::default()
             };
             m3u8_clips.push_front(seg);
         }
@@ -124,59 +575,403 @@ impl HLS {
             target_duration: td,
             props: m3u8_props,
             clips: m3u8_clips,
-            streams: m3u8_streams,
+            variants,
+            renditions,
+            can_block_reload,
+            part_target,
+            parts,
+            preload_hint: hint,
         };
         // info!("m3u8: {:?}", &m3u8);
         Ok(m3u8)
     }
 
-    fn parse_clip_url(&self, clip: &str) -> anyhow::Result<String> {
-        let url = if clip.starts_with("http") {
-            clip.to_string()
+    async fn get_key(&self, client: &Client, key: &SegmentKey) -> anyhow::Result<[u8; 16]> {
+        if let Some(it) = self.keys.borrow().get(&key.uri) {
+            return Ok(*it);
+        }
+        let resp = client.get(&key.uri).header("Connection", "keep-alive").send().await?.error_for_status()?;
+        let k: [u8; 16] = resp.bytes().await?.as_ref().try_into().map_err(|_| anyhow::anyhow!("invalid hls key"))?;
+        let mut keys = self.keys.borrow_mut();
+        if keys.len() > 16 {
+            keys.clear();
+        }
+        keys.insert(key.uri.clone(), k);
+        Ok(k)
+    }
+
+    async fn decrypt_clip(&self, client: &Client, clip: &MediaSegment, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let key = match clip.key.as_ref() {
+            Some(it) => it,
+            None => return Ok(data),
+        };
+        if key.method != KeyMethod::Aes128 {
+            return Err(anyhow::anyhow!(
+                "unsupported hls encryption method: {:?}",
+                key.method
+            ));
+        }
+        let k = self.get_key(client, key).await?;
+        let iv = key.iv.unwrap_or_else(|| (clip.sequence as u128).to_be_bytes());
+        type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+        let len = Aes128CbcDec::new(&k.into(), &iv.into())
+            .decrypt_padded_mut::<Pkcs7>(&mut data)
+            .map_err(|_| anyhow::anyhow!("hls segment decryption failed"))?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+
+    async fn get_socket(&self) -> anyhow::Result<Box<dyn DMLStream>> {
+        if self.audio {
+            self.ctx.im.get_audio_socket().await
         } else {
-            let url = url::Url::parse(&*self.url.borrow())?;
-            let url2 = url.join(&clip)?;
-            if url2.as_str().contains("?") {
-                url2.as_str().to_string()
-            } else {
-                format!("{}?{}", url2.as_str(), url.query().unwrap_or(""))
+            self.ctx.im.get_video_socket().await
+        }
+    }
+
+    async fn set_stream_ready(&self) {
+        if !self.stream_ready.get() {
+            self.stream_ready.set(true);
+            // the video track speaks for the whole stream
+            if self.audio.not() {
+                let _ = self.ctx.mtx.send(DMLMessage::StreamReady).await;
             }
-        };
-        Ok(url)
+        }
     }
 
     async fn download_task(&self, client: &Client, ss: &SegmentStream) -> anyhow::Result<()> {
-        let mut stream = self.ctx.im.get_video_socket().await?;
+        let mut stream = self.get_socket().await?;
         let mut rx = ss.clip_rx.borrow_mut();
-        while let Some(mut clip) = rx.recv().await {
-            // info!("hls: clip: {}", &clip);
+        let clips = futures::stream::poll_fn(|cx| rx.poll_recv(cx)).filter_map(|mut clip| {
             if self.header_done.get().not() && clip.is_header {
                 clip.skip = 0;
                 self.header_done.set(true);
             } else if clip.skip == 2 {
-                continue;
+                return std::future::ready(None);
             }
-            let url = self.parse_clip_url(&clip.url)?;
-            let mut resp = client.get(url).header("Connection", "keep-alive").send().await?;
-            while let Some(chunk) = resp.chunk().await? {
-                if clip.skip == 0 {
-                    if !self.stream_ready.get() {
-                        self.stream_ready.set(true);
-                        let _ = self.ctx.mtx.send(DMLMessage::StreamReady).await;
-                    }
-                    stream.write_all(&chunk).await?;
+            if self.skip_ad(&mut clip) {
+                return std::future::ready(None);
+            }
+            std::future::ready(Some(clip))
+        });
+        if self.ctx.cm.hls_prefetch <= 1 {
+            tokio::pin!(clips);
+            while let Some(clip) = clips.next().await {
+                self.handle_discontinuity(&clip, &mut stream).await?;
+                if clip.key.is_some() {
+                    self.write_clip(client, &clip, &mut stream).await?;
+                } else {
+                    self.stream_clip(client, &clip, &mut stream).await?;
+                }
+            }
+            return Ok(());
+        }
+        // segments are fetched ahead in parallel, but written out in playlist order
+        let mut fetched = clips
+            .map(|clip| async move {
+                let data = self.fetch_clip(client, &clip).await;
+                (clip, data)
+            })
+            .buffered(self.ctx.cm.hls_prefetch);
+        while let Some((clip, data)) = fetched.next().await {
+            let data = data?;
+            self.handle_discontinuity(&clip, &mut stream).await?;
+            self.output_clip(&clip, &data, &mut stream).await?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the ad break bookkeeping, true if the clip is an ad and has to be dropped.
+    fn skip_ad(&self, clip: &mut MediaSegment) -> bool {
+        if clip.ad {
+            // hold the last frame, ad segments never reach the player or recordings
+            let (start, du) = self.ad_break.get().unwrap_or_else(|| {
+                info!("hls ad break started");
+                if self.fallback.borrow().is_some() {
+                    self.fallback_pending.set(true);
                 }
+                (std::time::Instant::now(), 0.0)
+            });
+            self.ad_break.set(Some((start, du + clip.duration)));
+            self.watch_dog.set(true);
+            return true;
+        }
+        if let Some((start, du)) = self.ad_break.take()
+            && clip.skip == 0
+        {
+            info!(
+                "hls ad break over, {:.1}s of ads skipped in {}s",
+                du,
+                start.elapsed().as_secs()
+            );
+            clip.discontinuity = true;
+        }
+        false
+    }
+
+    async fn handle_discontinuity(&self, clip: &MediaSegment, stream: &mut Box<dyn DMLStream>) -> anyhow::Result<()> {
+        if clip.skip == 0 && clip.discontinuity && self.stream_ready.get() {
+            info!("hls discontinuity at sequence {}", clip.sequence);
+            if self.audio.not() && self.ctx.cm.stream_type.get() == StreamType::HLS(0) {
+                // let the pre ffmpeg drain and start over so that the timestamps restart cleanly; the flag
+                // is set before the socket closes, so the pre ffmpeg loop sees it when it ends
+                self.ctx.cm.restart_pre_ff.set(true);
+                stream.shutdown().await?;
+                *stream = self.get_socket().await?;
+            } else if self.ctx.cm.stream_type.get() == StreamType::HLS(1) {
+                // the mp4 demuxer reads one init section only and carries the jump into the output, a new
+                // ffmpeg with a new streamer starts over from the init section
+                return Err(anyhow::anyhow!("discontinuity in an fmp4 stream"));
+            }
+        }
+        Ok(())
+    }
+
+    fn clip_request(&self, client: &Client, clip: &MediaSegment) -> reqwest::RequestBuilder {
+        let req = client.get(&clip.url).header("Connection", "keep-alive");
+        match clip.byte_range {
+            Some((o, l)) => req.header("Range", format!("bytes={}-{}", o, o + l.max(1) - 1)),
+            None => req,
+        }
+    }
+
+    async fn fetch_clip(&self, client: &Client, clip: &MediaSegment) -> anyhow::Result<Vec<u8>> {
+        let req = self.clip_request(client, clip);
+        let now = std::time::Instant::now();
+        let data = req.send().await?.bytes().await?.to_vec();
+        if clip.is_header.not() && clip.duration > 0.0 {
+            self.update_throughput(client, data.len(), now.elapsed().as_secs_f64()).await;
+        }
+        self.decrypt_clip(client, clip, data).await
+    }
+
+    async fn update_throughput(&self, client: &Client, len: usize, elapsed: f64) {
+        let sample = len as f64 * 8.0 / elapsed.max(0.001);
+        let tp = match self.throughput.get() {
+            0.0 => sample,
+            it => it * 0.8 + sample * 0.2,
+        };
+        self.throughput.set(tp);
+        if self.audio {
+            return;
+        }
+        self.ctx.cm.throughput.set(tp as u64);
+        // segments in flight share the link, so each one only has to keep up with a part of the bandwidth
+        if tp * (self.ctx.cm.hls_prefetch as f64) < self.bandwidth.get() as f64 {
+            self.slow_count.update(|it| it + 1);
+        } else {
+            self.slow_count.set(0);
+        }
+        if self.slow_count.get() >= 5 {
+            self.slow_count.set(0);
+            if let Err(e) = self.downshift(client).await {
+                info!("hls downshift error: {}", e);
+            }
+        }
+    }
+
+    /// Switches to the best variant below the current bandwidth.
+    async fn downshift(&self, client: &Client) -> anyhow::Result<()> {
+        // a new init segment can not be fed to the running demuxer, only ts streams can switch
+        let master = match self.master.borrow().clone() {
+            Some(it) if self.ctx.cm.stream_type.get() == StreamType::HLS(0) => it,
+            _ => return Ok(()),
+        };
+        let m3u8 = self.fetch_m3u8(client, &master).await?;
+        let lower: Vec<_> = m3u8
+            .variants
+            .into_iter()
+            .filter(|it| it.bandwidth < self.bandwidth.get() && it.is_audio_only().not())
+            .collect();
+        let v = match self.spec.select(&lower) {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        info!(
+            "hls throughput {} kbit/s below {} kbit/s, switch to {:?}",
+            self.throughput.get() as u64 / 1000,
+            self.bandwidth.get() / 1000,
+            v
+        );
+        *self.url.borrow_mut() = v.url.clone();
+        self.bandwidth.set(v.bandwidth);
+        self.switched.set(true);
+        self.ctx.cm.downshifted.set(true);
+        Ok(())
+    }
+
+    async fn output_clip(
+        &self, clip: &MediaSegment, data: &[u8], stream: &mut Box<dyn DMLStream>,
+    ) -> anyhow::Result<()> {
+        if clip.skip == 0 {
+            self.start_output(clip).await;
+            stream.write_all(data).await?;
+            self.check_clip(clip, health::digest(data), health::first_pts(data)).await;
+        }
+        self.watch_dog.set(true);
+        Ok(())
+    }
+
+    /// Writes an unencrypted clip as it comes in, with one segment in flight there is nothing to hold it for.
+    async fn stream_clip(
+        &self, client: &Client, clip: &MediaSegment, stream: &mut Box<dyn DMLStream>,
+    ) -> anyhow::Result<()> {
+        let now = std::time::Instant::now();
+        let mut blocked = 0.0; // a paused player holds the writes, that is not the link being slow
+        let mut resp = self.clip_request(client, clip).send().await?;
+        let mut hasher = DefaultHasher::new();
+        let mut pts = None;
+        let mut len = 0;
+        while let Some(chunk) = resp.chunk().await? {
+            if len == 0 {
+                pts = health::first_pts(&chunk);
+            }
+            len += chunk.len();
+            hasher.write(&chunk);
+            if clip.skip == 0 {
+                self.start_output(clip).await;
+                let w = std::time::Instant::now();
+                stream.write_all(&chunk).await?;
+                blocked += w.elapsed().as_secs_f64();
             }
             self.watch_dog.set(true);
         }
+        if clip.is_header.not() && clip.duration > 0.0 {
+            self.update_throughput(client, len, now.elapsed().as_secs_f64() - blocked).await;
+        }
+        if clip.skip == 0 {
+            self.check_clip(clip, hasher.finish(), pts).await;
+        }
         Ok(())
     }
 
+    async fn start_output(&self, clip: &MediaSegment) {
+        if let Some(pdt) = clip.program_date_time
+            && self.audio.not()
+            && !self.stream_ready.get()
+        {
+            let delay = chrono::Utc::now().timestamp_millis() - pdt;
+            let _ = self.ctx.mtx.send(DMLMessage::SetStreamDelay(delay)).await;
+        }
+        self.set_stream_ready().await;
+    }
+
+    async fn check_clip(&self, clip: &MediaSegment, digest: u64, pts: Option<i64>) {
+        if self.audio.not() && clip.is_header.not() {
+            if clip.discontinuity {
+                self.health.reset();
+            }
+            self.health.check_segment(digest, pts).await;
+        }
+    }
+
+    async fn write_clip(
+        &self, client: &Client, clip: &MediaSegment, stream: &mut Box<dyn DMLStream>,
+    ) -> anyhow::Result<()> {
+        let data = self.fetch_clip(client, clip).await?;
+        self.output_clip(clip, &data, stream).await
+    }
+
+    async fn fetch_m3u8(&self, client: &Client, url: &str) -> anyhow::Result<M3U8> {
+        let m3u8_text = client.get(url).header("Connection", "keep-alive").send().await?.text().await?;
+        Self::decode_m3u8(&m3u8_text, url)
+    }
+
+    /// Fetches the media playlist, following a master playlist to the selected variant.
+    async fn load_media_playlist(&self, client: &Client) -> anyhow::Result<M3U8> {
+        let url = self.url.borrow().clone();
+        let m3u8 = self.fetch_m3u8(client, &url).await?;
+        if let Some(v) = self.spec.select(&m3u8.variants) {
+            *self.url.borrow_mut() = v.url.clone();
+            return self.fetch_m3u8(client, &v.url).await;
+        }
+        Ok(m3u8)
+    }
+
+    async fn blocking_reload(&self, client: &Client, msn: u64, part: u64) -> anyhow::Result<M3U8> {
+        let mut url = url::Url::parse(&self.url.borrow())?;
+        url.query_pairs_mut().append_pair("_HLS_msn", &msn.to_string()).append_pair("_HLS_part", &part.to_string());
+        self.fetch_m3u8(client, url.as_str()).await
+    }
+
+    /// Writes a part, a segment or the preload hint of a low latency playlist, dropping the ads like
+    /// download_task does.
+    async fn write_live_clip(
+        &self, client: &Client, clip: &MediaSegment, stream: &mut Box<dyn DMLStream>,
+    ) -> anyhow::Result<()> {
+        let mut clip = clip.clone();
+        if self.skip_ad(&mut clip) {
+            return Ok(());
+        }
+        self.handle_discontinuity(&clip, stream).await?;
+        self.write_clip(client, &clip, stream).await
+    }
+
+    async fn low_latency_task(&self, client: &Client, mut m3u8: M3U8) -> anyhow::Result<()> {
+        let mut stream = self.get_socket().await?;
+        if let Some(h) = m3u8.clips.iter().find(|it| it.is_header) {
+            let mut h = h.clone();
+            h.skip = 0;
+            self.write_clip(client, &h, &mut stream).await?;
+            self.header_done.set(true);
+        }
+        // start from the first part of the newest segment, which is independent
+        let (mut msn, mut part) = (m3u8.parts.last().map_or(m3u8.sequence, |it| it.0), 0);
+        loop {
+            if let Some((_, _, p)) = m3u8.parts.iter().find(|it| it.0 == msn && it.1 == part) {
+                self.write_live_clip(client, p, &mut stream).await?;
+                part += 1;
+                continue;
+            }
+            let media_end = m3u8.sequence + m3u8.clips.iter().filter(|it| !it.is_header).count() as u64;
+            if msn < media_end {
+                // the segment is complete, its parts are either all written or already aged out
+                if part == 0 {
+                    match m3u8.clips.iter().find(|it| !it.is_header && it.sequence == msn) {
+                        Some(seg) => self.write_live_clip(client, seg, &mut stream).await?,
+                        None => {
+                            info!("low latency hls: fell behind, jump to the live edge");
+                            msn = m3u8.parts.last().map_or(media_end, |it| it.0);
+                            continue;
+                        }
+                    }
+                }
+                msn += 1;
+                part = 0;
+                continue;
+            }
+            // the server holds back the preload hint response until the part is available
+            if let Some((_, _, hint)) = m3u8.preload_hint.as_ref().filter(|it| it.0 == msn && it.1 == part) {
+                self.write_live_clip(client, hint, &mut stream).await?;
+                part += 1;
+            }
+            m3u8 = match self.blocking_reload(client, msn, part).await {
+                Ok(it) => it,
+                Err(e) => {
+                    info!("{}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_secs_f64(
+                        m3u8.part_target.max(0.2),
+                    ))
+                    .await;
+                    m3u8
+                }
+            };
+        }
+    }
+
     async fn refresh_m3u8_task(&self, client: &Client, ss: &SegmentStream) -> anyhow::Result<()> {
         let mut rx = ss.refresh_rx.borrow_mut();
         while let Some(_) = rx.recv().await {
+            if self.fallback_pending.replace(false) {
+                match self.switch_to_fallback(client).await {
+                    Ok(_) => ss.reset(),
+                    Err(e) => info!("hls fallback playlist error: {}", e),
+                }
+            }
+            let url = self.url.borrow().clone();
             let resp = client
-                .get(&*self.url.borrow())
+                .get(&url)
                 .timeout(tokio::time::Duration::from_millis(ss.refresh_itvl.get()))
                 .header("Connection", "keep-alive")
                 .send()
@@ -195,17 +990,40 @@ impl HLS {
                     continue;
                 }
             };
-            let m3u8 = Self::decode_m3u8(&m3u8_text)?;
-            if m3u8.streams.is_empty().not() {
-                let (_, s) = m3u8.streams.iter().max_by(|a, b| a.0.cmp(&b.0)).unwrap();
-                let s = self.parse_clip_url(s)?;
-                *self.url.borrow_mut() = s;
+            let mut m3u8 = Self::decode_m3u8(&m3u8_text, &url)?;
+            if let Some(v) = self.spec.select(&m3u8.variants) {
+                *self.url.borrow_mut() = v.url.clone();
+            }
+            // the first new segment of the new variant restarts the timestamps
+            if self.switched.get()
+                && url == *self.url.borrow()
+                && let Some(it) = ss.first_new_clip(m3u8.sequence, &mut m3u8.clips)
+            {
+                self.switched.set(false);
+                it.discontinuity = true;
             }
             ss.update_sequence(m3u8.sequence, m3u8.clips, m3u8.target_duration * 1000).await?;
         }
         Ok(())
     }
 
+    /// Moves to the fallback playlist for the rest of the session.
+    async fn switch_to_fallback(&self, client: &Client) -> anyhow::Result<()> {
+        let fallback = match self.fallback.borrow_mut().take() {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        let m3u8 = self.fetch_m3u8(client, &fallback).await?;
+        let v = self.spec.select(&m3u8.variants).ok_or_else(|| anyhow::anyhow!("no hls variant found"))?;
+        info!("hls switch to fallback playlist: {:?}", v);
+        *self.url.borrow_mut() = v.url.clone();
+        self.bandwidth.set(v.bandwidth);
+        self.switched.set(true);
+        self.ctx.cm.downshifted.set(true);
+        *self.master.borrow_mut() = Some(fallback);
+        Ok(())
+    }
+
     async fn watch_dog_task(&self) -> anyhow::Result<()> {
         let mut cnt = 0;
         let max_waiting = match self.ctx.cm.site {
@@ -214,11 +1032,13 @@ impl HLS {
         };
         loop {
             tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-            if self.watch_dog.get().not() {
-                cnt += 1;
-            } else {
+            // a full timeshift cache holds up the downloads for as long as the player is paused
+            if self.watch_dog.get() || self.ctx.cm.paused.get() {
                 cnt = 0;
+            } else {
+                cnt += 1;
             }
+            self.ctx.cm.stream_idle.set(cnt);
             if cnt > max_waiting {
                 info!("watch dog failed!");
                 return Err(anyhow::anyhow!("watch dog failed!"));
@@ -232,6 +1052,19 @@ impl HLS {
             .user_agent(crate::utils::gen_ua())
             .timeout(tokio::time::Duration::from_secs(30))
             .build()?;
+        if self.ctx.cm.low_latency {
+            let m3u8 = self.load_media_playlist(&client).await?;
+            if m3u8.can_block_reload && m3u8.parts.is_empty().not() {
+                info!("low latency hls, part target {}s", m3u8.part_target);
+                tokio::select! {
+                    it = self.low_latency_task(&client, m3u8) => { it?; },
+                    it = self.watch_dog_task() => { it?; },
+                }
+                info!("hls streamer exit");
+                return Ok(());
+            }
+            info!("low latency hls not offered, fall back to full segments");
+        }
         let seg_stream = SegmentStream::new();
         tokio::select! {
             it = self.refresh_m3u8_task(&client, &seg_stream) => { it?; },
@@ -243,3 +1076,164 @@ impl HLS {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ad_parts() {
+        let m3u8 = HLS::decode_m3u8(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-PART-INF:PART-TARGET:1.0
+#EXT-X-MEDIA-SEQUENCE:10
+#EXT-X-DATERANGE:ID=\"stitched-ad-1\",START-DATE=\"2024-01-01T00:00:03Z\",DURATION=2.0
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00Z
+#EXT-X-PART:DURATION=1.0,URI=\"p10.0.ts\"
+#EXT-X-PART:DURATION=1.0,URI=\"p10.1.ts\"
+#EXTINF:2.0,Amazon
+s10.ts
+#EXT-X-PART:DURATION=1.0,URI=\"p11.0.ts\"
+#EXTINF:2.0,live
+s11.ts
+#EXT-X-PART:DURATION=1.0,URI=\"p12.0.ts\"
+#EXT-X-PART:DURATION=1.0,URI=\"p12.1.ts\"
+#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"p12.2.ts\"
+",
+            "https://example.com/live/index.m3u8?token=1",
+        )
+        .unwrap();
+        let ad: Vec<_> = m3u8.parts.iter().map(|(msn, i, p)| (*msn, *i, p.ad)).collect();
+        // 10 by its title, 11.0 and 12.x by the date range from 00:00:03 to 00:00:05
+        assert_eq!(
+            ad,
+            [
+                (10, 0, true),
+                (10, 1, true),
+                (11, 0, false),
+                (12, 0, true),
+                (12, 1, false)
+            ]
+        );
+        let (_, _, hint) = m3u8.preload_hint.unwrap();
+        assert_eq!(hint.program_date_time, Some(1704067206000));
+        assert!(hint.ad.not());
+    }
+
+    #[test]
+    fn absolute_urls() {
+        let m3u8 = HLS::decode_m3u8(
+            "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:1
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXT-X-KEY:METHOD=AES-128,URI=\"/keys/1\"
+#EXTINF:2.0,
+seg1.m4s
+#EXTINF:2.0,
+https://cdn.example.com/seg2.m4s
+",
+            "https://example.com/live/720p/index.m3u8?token=1",
+        )
+        .unwrap();
+        let urls: Vec<_> = m3u8.clips.iter().map(|it| it.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/live/720p/init.mp4?token=1",
+                "https://example.com/live/720p/seg1.m4s?token=1",
+                "https://cdn.example.com/seg2.m4s"
+            ]
+        );
+        assert_eq!(
+            m3u8.clips[1].key.as_ref().unwrap().uri,
+            "https://example.com/keys/1?token=1"
+        );
+    }
+
+    fn variant(bandwidth: u64, height: u64, fps: f64, codecs: &str) -> Variant {
+        Variant {
+            bandwidth,
+            resolution: Some((height * 16 / 9, height)),
+            codecs: codecs.to_string(),
+            frame_rate: fps,
+            url: format!("https://example.com/{height}p{fps}.m3u8"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn variant_spec() {
+        let spec = VariantSpec::parse("720p60, codec=avc1,lang=en");
+        assert_eq!((spec.max_height, spec.max_fps), (Some(720), Some(60.0)));
+        assert_eq!(
+            (spec.codec.as_deref(), spec.lang.as_deref()),
+            (Some("avc1"), Some("en"))
+        );
+        let spec = VariantSpec::parse("480,fps=30");
+        assert_eq!((spec.max_height, spec.max_fps), (Some(480), Some(30.0)));
+        // unknown terms leave the others alone
+        let spec = VariantSpec::parse("720p,best,fps=high");
+        assert_eq!((spec.max_height, spec.max_fps), (Some(720), None));
+        assert!(VariantSpec::parse("audio_only").audio_only);
+    }
+
+    #[test]
+    fn variant_select() {
+        let audio = Variant {
+            bandwidth: 160000,
+            codecs: "mp4a.40.2".into(),
+            name: "audio_only".into(),
+            ..Default::default()
+        };
+        let variants = [
+            variant(8000000, 1080, 60.0, "avc1.64002A,mp4a.40.2"),
+            variant(3000000, 720, 60.0, "avc1.4D401F,mp4a.40.2"),
+            variant(2000000, 720, 30.0, "hvc1.1.6.L93,mp4a.40.2"),
+            variant(1000000, 480, 30.0, "avc1.4D401F,mp4a.40.2"),
+            audio,
+        ];
+        let pick = |spec: &str| VariantSpec::parse(spec).select(&variants).map(|it| it.bandwidth);
+        assert_eq!(pick(""), Some(8000000));
+        assert_eq!(pick("720p"), Some(3000000));
+        assert_eq!(pick("720p30"), Some(2000000));
+        assert_eq!(pick("720,codec=avc1,fps=30"), Some(1000000));
+        assert_eq!(pick("audio_only"), Some(160000));
+        // nothing matches, the best one is taken
+        assert_eq!(pick("240"), Some(8000000));
+    }
+
+    #[test]
+    fn variant_select_audio() {
+        let rendition = |name: &str, language: &str, default: bool| Rendition {
+            media_type: "AUDIO".into(),
+            group_id: "aac".into(),
+            name: name.into(),
+            language: language.into(),
+            default,
+            url: Some(format!("https://example.com/{name}.m3u8")),
+        };
+        let renditions = [
+            rendition("English", "en-US", false),
+            rendition("Japanese", "ja", true),
+            Rendition {
+                group_id: "other".into(),
+                ..rendition("German", "de", false)
+            },
+        ];
+        let v = Variant {
+            audio: Some("aac".into()),
+            ..variant(1000000, 720, 30.0, "avc1")
+        };
+        let pick = |spec: &str| VariantSpec::parse(spec).select_audio(&renditions, &v).map(|it| it.name.as_str());
+        assert_eq!(pick("lang=en"), Some("English"));
+        assert_eq!(pick("lang=Japanese"), Some("Japanese"));
+        assert_eq!(pick(""), Some("Japanese"));
+        // another group
+        assert_eq!(pick("lang=de"), Some("Japanese"));
+        assert_eq!(
+            VariantSpec::parse("").select_audio(&renditions, &variant(1, 720, 30.0, "avc1")).map(|it| it.name.as_str()),
+            None
+        );
+    }
+}
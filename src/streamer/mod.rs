@@ -1,10 +1,16 @@
+pub mod dash;
 pub mod flv;
+pub mod health;
 pub mod hls;
 pub mod segment;
 pub mod youtube;
 
-use crate::{config::StreamType, dmlive::DMLContext};
-use std::{collections::HashMap, rc::Rc};
+use crate::{
+    config::{Site, StreamType},
+    dmlive::DMLContext,
+};
+use log::info;
+use std::{collections::HashMap, ops::Not, rc::Rc};
 
 pub struct Streamer {
     ctx: Rc<DMLContext>,
@@ -15,7 +21,61 @@ impl Streamer {
         Self { ctx }
     }
 
+    /// Sets "content_type" for urls whose extension does not tell the stream type, e.g. a manifest
+    /// behind an api path.
+    pub async fn sniff(&self, stream_info: &mut HashMap<&str, String>) {
+        let url = &stream_info["url"];
+        if url.starts_with("http").not()
+            || [".m3u8", ".flv", ".mpd"].iter().any(|it| url.contains(it))
+            || matches!(self.ctx.cm.site, Site::YoutubeLive | Site::BiliVideo | Site::TwitchVideo)
+        {
+            return;
+        }
+        match dash::DASH::content_type(url).await {
+            Ok(it) => {
+                stream_info.insert("content_type", it);
+            }
+            Err(e) => info!("content type error: {}", e),
+        }
+    }
+
+    /// Resolves what has to be known before ffmpeg starts, e.g. the HLS variant and audio rendition.
+    pub async fn prepare(&self, stream_info: &mut HashMap<&str, String>) -> anyhow::Result<()> {
+        self.ctx.cm.downshifted.set(false);
+        self.ctx.cm.stream_idle.set(0);
+        match self.ctx.cm.stream_type.get() {
+            StreamType::HLS(_) => {
+                let spec = hls::VariantSpec::parse(&self.ctx.cm.hls_variant);
+                // a resolved variant has been checked already
+                let checked = match hls::HLS::resolve_master(stream_info, &spec).await {
+                    Ok(_) => stream_info.contains_key("url_master"),
+                    Err(e) => {
+                        info!("resolve hls master playlist error: {}", e);
+                        false
+                    }
+                };
+                if checked.not() {
+                    hls::HLS::check_encryption(&stream_info["url"]).await?;
+                }
+            }
+            StreamType::MPD => {
+                dash::DASH::probe(stream_info).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     pub async fn run(&self, stream_info: &HashMap<&str, String>) -> anyhow::Result<()> {
+        let health = health::HealthMonitor::new(self.ctx.clone());
+        tokio::select! {
+            it = self.run_stream(stream_info) => { it?; },
+            it = health.site_task() => { it?; },
+        }
+        Ok(())
+    }
+
+    async fn run_stream(&self, stream_info: &HashMap<&str, String>) -> anyhow::Result<()> {
         match self.ctx.cm.stream_type.get() {
             StreamType::FLV => {
                 let s = flv::FLV::new(&stream_info, self.ctx.clone());
@@ -23,12 +83,24 @@ impl Streamer {
             }
             StreamType::HLS(_) => {
                 let s = hls::HLS::new(&stream_info, self.ctx.clone());
-                s.run().await?;
+                if stream_info.contains_key("url_a") {
+                    let a = hls::HLS::new_audio(stream_info, self.ctx.clone());
+                    tokio::select! {
+                        it = s.run() => { it?; },
+                        it = a.run() => { it?; },
+                    }
+                } else {
+                    s.run().await?;
+                }
             }
             StreamType::DASH => {
                 let s = youtube::Youtube::new(&stream_info, self.ctx.clone());
                 s.run().await?;
             }
+            StreamType::MPD => {
+                let s = dash::DASH::new(stream_info, self.ctx.clone());
+                s.run(stream_info.contains_key("url_a")).await?;
+            }
         }
         Ok(())
     }
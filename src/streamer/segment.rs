@@ -1,15 +1,37 @@
 use std::{
     cell::{Cell, RefCell},
-    collections::{HashMap, VecDeque},
+    collections::VecDeque,
+    ops::Not,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyMethod {
+    #[default]
+    None,
+    Aes128,
+    SampleAes,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SegmentKey {
+    pub method: KeyMethod,
+    pub uri: String,
+    pub iv: Option<[u8; 16]>, // derived from the media sequence number if absent
+}
+
 #[allow(unused)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MediaSegment {
     pub skip: usize, // 0: not skip, 1: download but do not output, 2: totally skip
     pub is_header: bool,
-    pub props: HashMap<String, String>,
+    pub sequence: u64,
+    pub key: Option<SegmentKey>,
+    pub duration: f64,                  // EXTINF, in seconds
+    pub discontinuity: bool,            // EXT-X-DISCONTINUITY before this segment
+    pub byte_range: Option<(u64, u64)>, // (offset, length)
+    pub program_date_time: Option<i64>, // in ms since the unix epoch
+    pub ad: bool,                       // part of a stitched-in ad break
     pub url: String,
 }
 
@@ -38,6 +60,25 @@ impl SegmentStream {
         }
     }
 
+    /// Forgets the played sequence, for switching to a playlist with an unrelated numbering.
+    pub fn reset(&self) {
+        self.sequence.set(0);
+        self.clips.borrow_mut().clear();
+    }
+
+    /// The first clip update_sequence will pass on unskipped, if any.
+    pub fn first_new_clip<'a>(&self, sq: u64, clips: &'a mut VecDeque<MediaSegment>) -> Option<&'a mut MediaSegment> {
+        let len = clips.len();
+        // a first update skips all but the newest clip
+        let first_update = self.clips.borrow().is_empty();
+        clips
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| self.sequence.get() < sq + *i as u64 && (first_update.not() || *i + 1 == len))
+            .map(|(_, it)| it)
+            .find(|it| it.is_header.not())
+    }
+
     pub async fn update_sequence(&self, sq: u64, clips: VecDeque<MediaSegment>, itvl: u64) -> anyhow::Result<()> {
         self.refresh_itvl.set(itvl);
         let mut old_clips = self.clips.borrow_mut();
@@ -169,9 +169,10 @@ impl Youtube {
             };
             let c = MediaSegment {
                 skip,
-                props: HashMap::new(),
+                sequence: sq,
                 url: sq.to_string(),
                 is_header: if state == 2 { true } else { false },
+                ..Default::default()
             };
             clips.push_back(c);
             ss.update_sequence(sq, clips, self.itvl.get()).await?;
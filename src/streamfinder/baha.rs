@@ -50,7 +50,7 @@ impl Baha {
             "title",
             format!("{}[{page}]", title.get(0..len).ok_or_else(|| dmlerr!())?),
         );
-        ret.insert("bili_cid", sn);
+        ret.insert("video_id", sn);
         // no video support
         ret.insert("url", "https://127.0.0.1".to_string());
         Ok(ret)
@@ -289,7 +289,7 @@ impl Bilibili {
             // let (bvid, cid, title, referer, _season_type) = self.get_page_info_ep(&u, page).await?;
             let (_bvid, cid, title, link) = self.get_page_info_ep(&u, page).await?;
             ret.insert("title", title);
-            ret.insert("bili_cid", cid);
+            ret.insert("video_id", cid);
             let resp =
                 client.get(&link).header("Referer", &link).header("Cookie", cookies).send().await?.text().await?;
             let re = Regex::new(r"const\s*playurlSSRData\s*=\s*(\{.+\})").unwrap();
@@ -333,7 +333,7 @@ impl Bilibili {
                 .await?;
             let j = j.pointer("/data").ok_or_else(|| dmlerr!())?;
             ret.insert("title", title);
-            ret.insert("bili_cid", cid);
+            ret.insert("video_id", cid);
             f1(&j, &mut ret)?;
         }
         Ok(ret)
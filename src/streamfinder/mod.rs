@@ -52,16 +52,21 @@ impl StreamFinder {
                     }
                     crate::config::Site::TwitchLive => {
                         let b = twitch::Twitch::new();
-                        b.get_live(&self.ctx.cm.room_url).await
+                        b.get_live(&self.ctx.cm.room_url, self.ctx.cm.ad_fallback).await
+                    }
+                    crate::config::Site::TwitchVideo => {
+                        let b = twitch::Twitch::new();
+                        b.get_video(&self.ctx.cm.room_url).await
                     }
                     crate::config::Site::YoutubeLive => {
                         let b = youtube::Youtube::new();
-                        b.get_live(&self.ctx.cm.room_url).await
+                        b.get_live(&self.ctx.cm.room_url, self.ctx.cm.prefer_hls).await
                     }
                     crate::config::Site::BahaVideo => {
                         let b = baha::Baha::new(self.ctx.clone());
                         b.get_video().await
                     }
+                    crate::config::Site::Local => return Err(anyhow!("local files are not streamed")),
                 };
                 match stream_info {
                     Ok(it) => {
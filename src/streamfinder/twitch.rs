@@ -1,11 +1,12 @@
 use crate::dmlerr;
-use log::info;
+use log::{debug, info};
 use regex::Regex;
 use std::collections::HashMap;
 use url::Url;
 
 const TTV_API1: &'static str = "https://gql.twitch.tv/gql";
 const TTV_API2: &'static str = "https://usher.ttvnw.net/api/channel/hls/{channel}.m3u8";
+const TTV_API3: &str = "https://usher.ttvnw.net/vod/{vid}.m3u8";
 
 pub async fn get_live_info(client: &reqwest::Client, rid: &str) -> anyhow::Result<(String, String, String, bool)> {
     let payload = format!(
@@ -21,7 +22,7 @@ pub async fn get_live_info(client: &reqwest::Client, rid: &str) -> anyhow::Resul
         .await?
         .json::<serde_json::Value>()
         .await?;
-    info!("{resp:?}");
+    debug!("{resp:?}");
     let mut is_live = false;
     let owner = resp.pointer("/data/user/displayName").and_then(|x| x.as_str()).ok_or_else(|| dmlerr!())?;
     let avatar = resp.pointer("/data/user/profileImageURL").and_then(|x| x.as_str()).ok_or_else(|| dmlerr!())?;
@@ -38,6 +39,42 @@ pub async fn get_live_info(client: &reqwest::Client, rid: &str) -> anyhow::Resul
     ))
 }
 
+pub async fn get_video_info(client: &reqwest::Client, vid: &str) -> anyhow::Result<(String, String)> {
+    let payload = format!(
+        r#"{{ "query": "query VideoInfo($id: ID!) {{ video(id: $id) {{ title owner {{ displayName login }} }} }}", "variables": {{ "id": "{vid}" }} }}"#,
+    );
+    let resp = client
+        .post(TTV_API1)
+        .header("User-Agent", crate::utils::gen_ua())
+        .header("Referer", "https://m.twitch.tv/")
+        .header("Client-Id", "kimne78kx3ncx6brgo4mv6wki5h1ko")
+        .body(payload)
+        .send()
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+    debug!("{resp:?}");
+    let owner = resp.pointer("/data/video/owner/displayName").and_then(|x| x.as_str()).ok_or_else(|| dmlerr!())?;
+    let title = resp.pointer("/data/video/title").and_then(|x| x.as_str()).unwrap_or("没有视频标题");
+    Ok((owner.to_string(), title.to_string()))
+}
+
+fn select_variant(m3u8: &str) -> anyhow::Result<String> {
+    let re = Regex::new(r#"BANDWIDTH=([0-9]+)[^\n]+\n(http[^\n]+)"#).unwrap();
+    let url = re
+        .captures_iter(m3u8)
+        .map(|x| {
+            (
+                x.get(1).map_or("1", |x| x.as_str()),
+                x.get(2).map_or("aaaa", |x| x.as_str()),
+            )
+        })
+        .max_by_key(|x| x.0.parse::<i64>().unwrap_or(0))
+        .ok_or_else(|| dmlerr!())?
+        .1;
+    Ok(url.to_string())
+}
+
 pub struct Twitch {}
 
 impl Twitch {
@@ -45,7 +82,7 @@ impl Twitch {
         Self {}
     }
 
-    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<HashMap<&'static str, String>> {
+    pub async fn get_live(&self, room_url: &str, ad_fallback: bool) -> anyhow::Result<HashMap<&'static str, String>> {
         let rid = Url::parse(room_url)?.path_segments().and_then(|x| x.last()).ok_or_else(|| dmlerr!())?.to_string();
         let client = reqwest::Client::new();
         let mut ret = HashMap::new();
@@ -53,9 +90,36 @@ impl Twitch {
         let room_info = get_live_info(&client, &rid).await?;
         room_info.3.then(|| 0).ok_or_else(|| dmlerr!())?;
         ret.insert("title", format!("{} - {}", room_info.1, room_info.0));
+        let api2 = self.get_live_master(&client, &rid, "pulsar").await?;
+        let resp = client
+            .get(api2.as_str())
+            .header("User-Agent", crate::utils::gen_ua())
+            .header("Accept-Language", "en-US")
+            .header("Referer", "https://m.twitch.tv/")
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        // println!("{}", &resp);
+        ret.insert("url", select_variant(&resp)?);
+        ret.insert("url_master", api2.into());
+        if ad_fallback {
+            // embedded players get a different ad decision, switched to during ad breaks
+            match self.get_live_master(&client, &rid, "embed").await {
+                Ok(it) => {
+                    ret.insert("url_fallback", it.into());
+                }
+                Err(e) => info!("twitch fallback playlist error: {}", e),
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn get_live_master(&self, client: &reqwest::Client, rid: &str, player_type: &str) -> anyhow::Result<Url> {
         let mut param1 = Vec::new();
         let payload = format!(
-            r#"{{"query": "query {{ streamPlaybackAccessToken(channelName: \"{rid}\", params: {{ platform: \"web\", playerBackend:\"mediaplayer\", playerType:\"pulsar\" }}) {{ value, signature }} }}"}}"#,
+            r#"{{"query": "query {{ streamPlaybackAccessToken(channelName: \"{rid}\", params: {{ platform: \"web\", playerBackend:\"mediaplayer\", playerType:\"{player_type}\" }}) {{ value, signature }} }}"}}"#,
         );
         let resp = client
             .post(TTV_API1)
@@ -74,14 +138,55 @@ impl Twitch {
             .ok_or_else(|| dmlerr!())?;
         let token =
             resp.pointer("/data/streamPlaybackAccessToken/value").and_then(|x| x.as_str()).ok_or_else(|| dmlerr!())?;
-        param1.clear();
         param1.push(("allow_source", "true"));
         param1.push(("fast_bread", "true"));
         param1.push(("sig", sign));
         param1.push(("token", token));
-        let api2 = TTV_API2.replace("{channel}", &rid);
+        Ok(Url::parse_with_params(
+            &TTV_API2.replace("{channel}", rid),
+            &param1,
+        )?)
+    }
+
+    pub async fn get_video(&self, video_url: &str) -> anyhow::Result<HashMap<&'static str, String>> {
+        let vid = Url::parse(video_url)?
+            .path_segments()
+            .and_then(|mut x| x.find(|s| s.chars().all(|c| c.is_ascii_digit()) && !s.is_empty()))
+            .ok_or_else(|| dmlerr!())?
+            .to_string();
+        let client = reqwest::Client::new();
+        let mut ret = HashMap::new();
+
+        let video_info = get_video_info(&client, &vid).await?;
+        ret.insert("title", format!("{} - {}", video_info.1, video_info.0));
+        let payload = format!(
+            r#"{{"query": "query {{ videoPlaybackAccessToken(id: \"{vid}\", params: {{ platform: \"web\", playerBackend:\"mediaplayer\", playerType:\"site\" }}) {{ value, signature }} }}"}}"#,
+        );
         let resp = client
-            .get(api2)
+            .post(TTV_API1)
+            .header("User-Agent", crate::utils::gen_ua())
+            .header("Referer", "https://m.twitch.tv/")
+            .header("Client-Id", "kimne78kx3ncx6brgo4mv6wki5h1ko")
+            .body(payload)
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+        let sign = resp
+            .pointer("/data/videoPlaybackAccessToken/signature")
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| dmlerr!())?;
+        let token =
+            resp.pointer("/data/videoPlaybackAccessToken/value").and_then(|x| x.as_str()).ok_or_else(|| dmlerr!())?;
+        let param1 = vec![
+            ("allow_source", "true"),
+            ("allow_audio_only", "true"),
+            ("sig", sign),
+            ("token", token),
+        ];
+        let api3 = TTV_API3.replace("{vid}", &vid);
+        let resp = client
+            .get(api3)
             .header("User-Agent", crate::utils::gen_ua())
             .header("Accept-Language", "en-US")
             .header("Referer", "https://m.twitch.tv/")
@@ -90,21 +195,8 @@ impl Twitch {
             .await?
             .text()
             .await?;
-
-        // println!("{}", &resp);
-        let re = Regex::new(r#"BANDWIDTH=([0-9]+)[^\n]+\n(http[^\n]+)"#).unwrap();
-        let url = re
-            .captures_iter(&resp)
-            .map(|x| {
-                (
-                    x.get(1).map_or("1", |x| x.as_str()),
-                    x.get(2).map_or("aaaa", |x| x.as_str()),
-                )
-            })
-            .max_by_key(|x| x.0.parse::<i64>().unwrap_or(0))
-            .ok_or_else(|| dmlerr!())?
-            .1;
-        ret.insert("url", url.to_string());
+        ret.insert("url", select_variant(&resp)?);
+        ret.insert("video_id", vid);
         Ok(ret)
     }
 }
@@ -1,13 +1,13 @@
 use log::{debug, info};
 use regex::Regex;
 use reqwest::Client;
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Not};
 
 use crate::{dmlerr, utils};
 
 pub async fn get_live_info(
     client: &Client, room_url: &str,
-) -> anyhow::Result<(String, String, String, bool, String, String)> {
+) -> anyhow::Result<(String, String, String, bool, String, String, String)> {
     let resp = client
         .get(room_url)
         .header("Accept-Language", "en-US")
@@ -45,7 +45,7 @@ pub async fn get_live_info(
     let is_live = j.and_then(|x| x.pointer("/videoDetails/isLive")?.as_bool()).unwrap_or(false);
 
     let mpd_url = j.and_then(|x| x.pointer("/streamingData/dashManifestUrl")?.as_str()).unwrap_or("");
-    // let hls_url = j.pointer("/streamingData/hlsManifestUrl").ok_or_else(|| dmlerr!())?.as_str().unwrap();
+    let hls_url = j.and_then(|x| x.pointer("/streamingData/hlsManifestUrl")?.as_str()).unwrap_or("");
 
     Ok((
         owner.to_string(),
@@ -54,6 +54,7 @@ pub async fn get_live_info(
         is_live,
         cid.to_string(),
         mpd_url.to_string(),
+        hls_url.to_string(),
     ))
 }
 
@@ -138,7 +139,6 @@ impl Youtube {
         }
     }
 
-    #[allow(dead_code)]
     pub async fn decode_m3u8(client: &Client, url: &str) -> anyhow::Result<String> {
         let resp = client
             .get(url)
@@ -149,21 +149,31 @@ impl Youtube {
             .await?
             .text()
             .await?;
-        let mut m3u8_url = "";
+        let mut bw = 0u64;
+        let mut best = (0u64, "");
         for l in resp.lines() {
-            info!("{l}");
-            if l.contains(".m3u8") {
-                m3u8_url = l;
+            debug!("{l}");
+            let l = l.trim();
+            if let Some(inf) = l.strip_prefix("#EXT-X-STREAM-INF:") {
+                bw = inf
+                    .split(',')
+                    .find_map(|a| a.trim().strip_prefix("BANDWIDTH=")?.parse().ok())
+                    .unwrap_or(1);
+            } else if !l.is_empty() && !l.starts_with('#') {
+                if bw >= best.0 {
+                    best = (bw, l);
+                }
+                bw = 0;
             }
         }
-        if m3u8_url.is_empty() {
+        if best.1.is_empty() {
             Err(anyhow::anyhow!("no m3u8 url found"))
         } else {
-            Ok(m3u8_url.to_string())
+            Ok(url::Url::parse(url)?.join(best.1)?.to_string())
         }
     }
 
-    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<HashMap<&'static str, String>> {
+    pub async fn get_live(&self, room_url: &str, prefer_hls: bool) -> anyhow::Result<HashMap<&'static str, String>> {
         let client = reqwest::Client::builder()
             .user_agent(utils::gen_ua())
             .timeout(tokio::time::Duration::from_secs(10))
@@ -184,8 +194,15 @@ impl Youtube {
         info!("{room_info:?}");
         room_info.3.then(|| 0).ok_or_else(|| dmlerr!())?;
 
-        // let urls = self.decode_m3u8(&client, &hls_url).await?;
-        let mut ret = Self::decode_mpd(&client, &room_info.5).await?;
+        let mut ret = if room_info.6.is_empty().not() && (prefer_hls || room_info.5.is_empty()) {
+            info!("youtube: using hls manifest");
+            let mut ret = HashMap::new();
+            ret.insert("url", Self::decode_m3u8(&client, &room_info.6).await?);
+            ret.insert("url_master", room_info.6.clone());
+            ret
+        } else {
+            Self::decode_mpd(&client, &room_info.5).await?
+        };
 
         ret.insert("title", format!("{} - {}", room_info.1, room_info.0));
         ret.insert("room_url", room_url);